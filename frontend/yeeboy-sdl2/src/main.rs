@@ -4,10 +4,23 @@ extern crate yeeboy;
 
 use yeeboy::cartridge::Cartridge;
 use yeeboy::console::Console;
+use yeeboy::coverage::Coverage;
+use yeeboy::debugger::{Debugger, Stop};
+use yeeboy::disasm;
+use yeeboy::gdb::GdbStub;
+use yeeboy::gpu::{DebugPalette, TileMap};
 use yeeboy::input;
+use yeeboy::link::{self, LinkCable};
+use yeeboy::palette::{ColorCorrection, Palette, Palettes, Preset};
+use yeeboy::printer::Printer;
+use yeeboy::profiler::Profiler;
+use yeeboy::symbols::Symbols;
+use yeeboy::trace::{TraceFormat, Tracer};
 
+use std::cell::RefCell;
 use std::fs::File;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::time::{Instant, Duration};
 use std::io::prelude::*;
 
@@ -24,7 +37,78 @@ struct Opts {
     rom: PathBuf,
     #[clap(long, short)]
     trace: bool,
+    /// Trace format: yeeboy, or doctor for gameboy-doctor logs
+    #[clap(long, default_value = "yeeboy")]
+    trace_format: String,
+    /// Write the trace to a file instead of the terminal. Implies --trace.
+    #[clap(long)]
+    trace_file: Option<PathBuf>,
+    /// Start tracing when PC reaches this address, in hex
+    #[clap(long, parse(try_from_str = parse_hex))]
+    trace_start_pc: Option<u16>,
+    /// Start tracing after this many cycles
+    #[clap(long)]
+    trace_start_cycle: Option<u64>,
+    /// Stop tracing after this many lines
+    #[clap(long)]
+    trace_max_lines: Option<u64>,
+    /// Block VRAM and OAM accesses while the PPU uses them, like the hardware does
+    #[clap(long)]
+    restrict_access: bool,
+    /// Output palette: dmg, pocket, light, bgb, cgb or 4 comma separated hex colors
+    #[clap(long)]
+    palette: Option<String>,
+    /// Apply the CGB LCD color correction
+    #[clap(long)]
+    color_correction: bool,
+    /// Start paused in the debugger. Commands are read from the terminal.
+    #[clap(long, short)]
+    debug: bool,
+    /// Wait for a GDB remote protocol client on this local port
+    #[clap(long)]
+    gdb: Option<u16>,
+    /// Wait for another instance to plug the other end of a link cable on this local port
+    #[clap(long, conflicts_with_all = &["link-connect", "link-rom", "printer"])]
+    link_listen: Option<u16>,
+    /// Plug a link cable into another instance listening at this address, e.g. localhost:2346
+    #[clap(long, conflicts_with_all = &["link-listen", "link-rom", "printer"])]
+    link_connect: Option<String>,
+    /// Run a second console with this ROM, linked by cable and shown side by side. Tab switches the
+    /// console controlled by the keyboard.
+    #[clap(long, conflicts_with_all = &["link-listen", "link-connect", "printer"])]
+    link_rom: Option<PathBuf>,
+    /// Plug a Game Boy Printer into the link port. Printed sheets are saved as PNG in this directory.
+    #[clap(long, conflicts_with_all = &["link-listen", "link-connect", "link-rom"])]
+    printer: Option<PathBuf>,
+    /// Profile the execution and print the hottest functions on exit
+    #[clap(long)]
+    profile: bool,
+    /// Write the profile as folded stacks for flamegraph.pl or inferno. Implies --profile.
+    #[clap(long)]
+    flamegraph: Option<PathBuf>,
+    /// RGBDS symbol file. Defaults to the ROM path with a .sym extension, if it exists.
+    #[clap(long)]
+    sym: Option<PathBuf>,
+    /// Code/Data Logger file, recording which ROM bytes were executed or read. Merged with the existing file, if any.
+    #[clap(long)]
+    cdl: Option<PathBuf>,
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
 }
+
+#[derive(Clap,Debug)]
+enum SubCommand {
+    /// Disassemble the ROM to RGBDS syntax instead of running it
+    Disasm(Disasm),
+}
+
+#[derive(Clap,Debug)]
+struct Disasm {
+    /// ROM bank to disassemble, all of them by default
+    #[clap(long, short)]
+    bank: Option<usize>,
+}
+
 struct YeeboyWindow {
     pub canvas: WindowCanvas,
     texture: Texture,
@@ -34,8 +118,8 @@ struct YeeboyWindow {
 }
 
 impl YeeboyWindow {
-    pub fn new(width: u32, height: u32, video: &VideoSubsystem) -> Self {
-        let canvas = Self::make_canvas(width * 3, height * 3, video);
+    pub fn new(title: &str, width: u32, height: u32, video: &VideoSubsystem) -> Self {
+        let canvas = Self::make_canvas(title, width * 3, height * 3, video);
         let texture_creator = canvas.texture_creator();
         let texture = texture_creator.create_texture_target(PixelFormatEnum::RGBA32, width, height).unwrap();
 
@@ -66,8 +150,8 @@ impl YeeboyWindow {
     pub fn hide(&mut self) { self.canvas.window_mut().hide() }
     pub fn show(&mut self) { self.canvas.window_mut().show() }
 
-    fn make_canvas(width: u32, height: u32, video: &VideoSubsystem) -> WindowCanvas {
-        let window = video.window("OAM Viewer", width, height)
+    fn make_canvas(title: &str, width: u32, height: u32, video: &VideoSubsystem) -> WindowCanvas {
+        let window = video.window(title, width, height)
             .resizable()
             .position(0, 0)
             .allow_highdpi()
@@ -87,28 +171,169 @@ impl YeeboyWindow {
 fn main() {
     let opts = Opts::parse();
 
-    let mut file = File::open(opts.rom).unwrap();
+    let mut file = File::open(&opts.rom).unwrap();
     let mut rom = Vec::new();
     file.read_to_end(&mut rom).expect("Cannot read file");
+
+    let symbols = match opts.sym.as_ref() {
+        Some(path) => Symbols::parse(&std::fs::read_to_string(path).expect("Cannot read symbol file")),
+        None => match std::fs::read_to_string(opts.rom.with_extension("sym")) {
+            Ok(text) => Symbols::parse(&text),
+            Err(_) => Symbols::new(),
+        },
+    };
+
+    if let Some(SubCommand::Disasm(args)) = opts.subcmd {
+        let banks = (rom.len() + disasm::BANK_SIZE - 1) / disasm::BANK_SIZE;
+        match args.bank {
+            Some(bank) if bank >= banks => panic!("The ROM only has {} banks", banks),
+            Some(bank) => print!("{}", disasm::disassemble_bank(&rom, bank, &symbols)),
+            None => {
+                for bank in 0..banks {
+                    println!("{}", disasm::disassemble_bank(&rom, bank, &symbols));
+                }
+            }
+        }
+        return;
+    }
+
     let cartridge = Cartridge::load(rom);
     dbg!(&cartridge.headers);
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
-    let mut oam = YeeboyWindow::new(160, 144, &video_subsystem);
-    let mut window = YeeboyWindow::new(160, 144, &video_subsystem);
+    let mut console = Console::new(cartridge, false);
+    if opts.trace || opts.trace_file.is_some() {
+        let format = TraceFormat::from_name(&opts.trace_format)
+            .unwrap_or_else(|| panic!("Unknown trace format {}", opts.trace_format));
+        let mut tracer = match opts.trace_file {
+            Some(ref path) => Tracer::file(path, format).expect("Cannot create trace file"),
+            None => Tracer::stdout(format),
+        };
+        tracer.start_pc = opts.trace_start_pc;
+        tracer.start_cycle = opts.trace_start_cycle;
+        tracer.max_lines = opts.trace_max_lines;
+        console.cpu.tracer = Some(tracer);
+    }
+    console.cpu.memory.restrict_access = opts.restrict_access;
+    console.cpu.symbols = symbols;
+    if opts.profile || opts.flamegraph.is_some() {
+        console.profiler = Some(Profiler::new());
+    }
+    let cable = match (opts.link_listen, opts.link_connect.as_ref()) {
+        (Some(port), _) => {
+            println!("Waiting for the link cable on port {}", port);
+            Some(LinkCable::listen(port))
+        }
+        (None, Some(address)) => Some(LinkCable::connect(address.as_str())),
+        (None, None) => None,
+    };
+    if let Some(cable) = cable {
+        console.cpu.memory.serial.device = Some(Box::new(cable.expect("Cannot connect the link cable")));
+    }
+    let printer = opts.printer.as_ref().map(|directory| Rc::new(RefCell::new(Printer::new(Some(directory.clone())))));
+    if let Some(printer) = printer.as_ref() {
+        console.cpu.memory.serial.device = Some(Box::new(printer.clone()));
+    }
+    let mut second = opts.link_rom.as_ref().map(|path| {
+        let rom = std::fs::read(path).expect("Cannot read file");
+        let mut second = Console::new(Cartridge::load(rom), false);
+        link::connect(&mut console, &mut second);
+        second
+    });
+    // Console controlled by the keyboard, 1 for the second one
+    let mut player = 0;
+    if let Some(ref path) = opts.cdl {
+        let rom_size = console.cpu.memory.headers().rom_size;
+        console.cpu.memory.coverage = Some(Coverage::load(path, rom_size).unwrap_or_else(|_| Coverage::new(rom_size)));
+    }
+
+    if let Some(name) = opts.palette {
+        if let Some(preset) = Preset::from_name(&name) {
+            console.set_palette_preset(preset);
+        } else if let Some(palette) = Palette::from_hex(&name) {
+            console.set_palettes(Palettes::uniform(palette));
+        } else {
+            panic!("Unknown palette {}", name);
+        }
+    }
+
+    if opts.color_correction {
+        console.set_color_correction(ColorCorrection::Cgb);
+    }
+
+    let (oam_width, oam_height) = console.cpu.memory.gpu.debug_sprites_size();
+    let mut oam = YeeboyWindow::new("OAM Viewer", oam_width as u32, oam_height as u32, &video_subsystem);
+    let (table_width, table_height) = console.cpu.memory.gpu.debug_oam_table_size();
+    let mut oam_table = YeeboyWindow::new("OAM Table", table_width as u32, table_height as u32, &video_subsystem);
+    oam_table.toggle();
+    let (tiles_width, tiles_height) = console.cpu.memory.gpu.debug_tiles_size();
+    let mut tiles = YeeboyWindow::new("Tile Viewer", tiles_width as u32, tiles_height as u32, &video_subsystem);
+    let mut tiles_palette = DebugPalette::Bgp;
+    tiles.toggle();
+    let mut background = YeeboyWindow::new("Background Viewer", 256, 256, &video_subsystem);
+    let mut background_map = TileMap::Map9800;
+    background.toggle();
+    let mut palettes = YeeboyWindow::new("Palettes", 64, 48, &video_subsystem);
+    palettes.toggle();
+    let screens = if second.is_some() { 2 } else { 1 };
+    let mut window = YeeboyWindow::new("YeeBoy", 160 * screens, 144, &video_subsystem);
 
     let mut event_pump = sdl_context.event_pump().unwrap();
     let mut now = Instant::now();
-    let mut console = Console::new(cartridge, opts.trace);
+    let mut debugger = if opts.debug { Some(Debugger::new()) } else { None };
+    let mut gdb = opts.gdb.map(|port| {
+        println!("Waiting for gdb on port {}", port);
+        GdbStub::listen(port).expect("Cannot start the gdb stub")
+    });
 
     'running: loop {
-        console.step();
+        if let Some(stub) = gdb.as_mut() {
+            match stub.step(&mut console) {
+                Ok(true) => {}
+                Ok(false) => gdb = None,
+                Err(err) => {
+                    println!("gdb: {}", err);
+                    gdb = None;
+                }
+            }
+        } else {
+            match debugger.as_mut() {
+                Some(debugger) if debugger.is_paused() => {
+                    if !repl(debugger, &mut console) {
+                        break 'running;
+                    }
+                    continue;
+                }
+                Some(debugger) => {
+                    if let Some(stop) = debugger.tick(&mut console) {
+                        println!("{}", stop);
+                        println!("{}", debugger.state(&mut console));
+                    }
+                }
+                None => console.step(),
+            }
+        }
+
+        if let Some(second) = second.as_mut() {
+            second.run_until(console.cpu.cycles);
+        }
 
         if console.new_frame() {
-            window.update(&console.cpu.memory.gpu.frame);
+            match second.as_ref() {
+                Some(second) => window.update(&side_by_side(console.frame(), second.frame())),
+                None => window.update(console.frame()),
+            }
             oam.update(&console.cpu.memory.gpu.render_debug_sprites());
+            oam_table.update(&console.cpu.memory.gpu.render_debug_oam_table());
+            tiles.update(&console.cpu.memory.gpu.render_debug_tiles(tiles_palette));
+            background.update(&console.cpu.memory.gpu.render_debug_background(background_map));
+            palettes.update(&console.cpu.memory.gpu.render_debug_palettes());
+
+            if let Some(error) = printer.as_ref().and_then(|printer| printer.borrow_mut().error.take()) {
+                println!("Cannot save the printed sheet: {}", error);
+            }
 
             // This should be outside of the new_frame condition but due to
             // a perf regression in SDL 2.0.9 we have to leave it here to
@@ -118,17 +343,60 @@ fn main() {
                     Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                         break 'running
                     },
+                    Event::KeyDown { keycode: Some(Keycode::F12), .. } => {
+                        if let Some(debugger) = debugger.as_mut() {
+                            debugger.pause();
+                            println!("{}", Stop::Interrupted);
+                            println!("{}", debugger.state(&mut console));
+                        }
+                    }
                     Event::KeyDown { keycode: Some(Keycode::O), .. } => {
                         oam.toggle();
                     }
+                    Event::KeyDown { keycode: Some(Keycode::T), .. } => {
+                        tiles.toggle();
+                    }
+                    Event::KeyDown { keycode: Some(Keycode::B), .. } => {
+                        background.toggle();
+                    }
+                    Event::KeyDown { keycode: Some(Keycode::M), .. } => {
+                        background_map = match background_map {
+                            TileMap::Map9800 => TileMap::Map9C00,
+                            TileMap::Map9C00 => TileMap::Map9800,
+                        };
+                    }
+                    Event::KeyDown { keycode: Some(Keycode::L), .. } => {
+                        palettes.toggle();
+                    }
+                    Event::KeyDown { keycode: Some(Keycode::D), .. } => {
+                        oam_table.toggle();
+                    }
+                    Event::KeyDown { keycode: Some(Keycode::P), .. } => {
+                        tiles_palette = match tiles_palette {
+                            DebugPalette::Bgp => DebugPalette::Obp0,
+                            DebugPalette::Obp0 => DebugPalette::Obp1,
+                            DebugPalette::Obp1 => DebugPalette::Identity,
+                            DebugPalette::Identity => DebugPalette::Bgp,
+                        };
+                    }
+                    Event::KeyDown { keycode: Some(Keycode::Tab), .. } if second.is_some() => {
+                        player = 1 - player;
+                        println!("Controlling the {} console", if player == 0 { "left" } else { "right" });
+                    }
                     Event::KeyDown { keycode: Some(keycode), .. } => {
                         if let Some(button) = keycode_to_button(keycode) {
-                            console.key_down(button);
+                            match second.as_mut() {
+                                Some(second) if player == 1 => second.key_down(button),
+                                _ => console.key_down(button),
+                            }
                         }
                     }
                     Event::KeyUp { keycode: Some(keycode), .. } => {
                         if let Some(button) = keycode_to_button(keycode) {
-                            console.key_up(button);
+                            match second.as_mut() {
+                                Some(second) if player == 1 => second.key_up(button),
+                                _ => console.key_up(button),
+                            }
                         }
                     }
                     _ => {}
@@ -147,6 +415,56 @@ fn main() {
             }
         }
     }
+
+    if let Some(profiler) = console.profiler.as_ref() {
+        println!("{}", profiler.report(&console.cpu.symbols, 20));
+        if let Some(path) = opts.flamegraph {
+            std::fs::write(path, profiler.folded_stacks(&console.cpu.symbols)).expect("Cannot write the flamegraph");
+        }
+    }
+
+    if let (Some(coverage), Some(path)) = (console.cpu.memory.coverage.as_ref(), opts.cdl.as_ref()) {
+        let (code, data, unused) = coverage.summary();
+        println!("Coverage: {} bytes of code, {} bytes of data, {} bytes unused", code, data, unused);
+        coverage.save(path).expect("Cannot write the CDL file");
+    }
+}
+
+// Read debugger commands from the terminal until the execution resumes.
+// Returns false when the user wants to quit.
+fn repl(debugger: &mut Debugger, console: &mut Console) -> bool {
+    let stdin = std::io::stdin();
+
+    while debugger.is_paused() {
+        print!("> ");
+        std::io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line).unwrap_or(0) == 0 {
+            return false;
+        }
+
+        match line.trim() {
+            "q" | "quit" => return false,
+            line => match debugger.command(console, line) {
+                Ok(output) if output.is_empty() => {}
+                Ok(output) => println!("{}", output),
+                Err(err) => println!("{}", err),
+            },
+        }
+    }
+
+    true
+}
+
+fn parse_hex(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s.trim_start_matches('$').trim_start_matches("0x"), 16)
+}
+
+// Two RGBA frames in one, the first on the left
+fn side_by_side(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let row = 160 * 4;
+    left.chunks(row).zip(right.chunks(row)).flat_map(|(l, r)| l.iter().chain(r)).copied().collect()
 }
 
 fn keycode_to_button(keycode: Keycode) -> Option<input::Button> {
@@ -39,6 +39,15 @@ impl Console {
         self.console.frame().as_ptr()
     }
 
+    pub fn indexed_frame(&self) -> *const u8 {
+        self.console.indexed_frame().as_ptr()
+    }
+
+    // One byte per pixel: 0 = background, 1 = window, 2 = OBJ0, 3 = OBJ1
+    pub fn layers(&self) -> *const u8 {
+        self.console.layers().as_ptr() as *const u8
+    }
+
     pub fn pc(&self) -> u16 {
         self.console.cpu.pc
     }
@@ -63,6 +72,38 @@ impl Console {
         }
     }
 
+    pub fn set_palette(&mut self, name: &str) -> bool {
+        match yeeboy::palette::Preset::from_name(name) {
+            Some(preset) => {
+                self.console.set_palette_preset(preset);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // JSON array describing the 40 OAM entries
+    pub fn oam(&self) -> String {
+        let entries = self.console.cpu.memory.gpu.oam_table().iter().map(|e| {
+            format!(
+                "{{\"index\":{},\"x\":{},\"y\":{},\"tile\":{},\"attrs\":{},\"xFlip\":{},\"yFlip\":{},\"behindBg\":{},\"palette\":{},\"onLine\":{}}}",
+                e.index, e.x, e.y, e.tile, e.attrs, e.x_flip, e.y_flip, e.behind_bg, e.palette, e.on_line,
+            )
+        }).collect::<Vec<String>>();
+        format!("[{}]", entries.join(","))
+    }
+
+    // JSON array of the palettes, with their register value and CSS colors
+    pub fn palettes(&self) -> String {
+        let palettes = self.console.cpu.memory.gpu.debug_palettes().iter().map(|(name, value, colors)| {
+            let colors = colors.iter()
+                .map(|(r, g, b)| format!("\"#{:02X}{:02X}{:02X}\"", r, g, b))
+                .collect::<Vec<String>>();
+            format!("{{\"name\":\"{}\",\"value\":{},\"colors\":[{}]}}", name, value, colors.join(","))
+        }).collect::<Vec<String>>();
+        format!("[{}]", palettes.join(","))
+    }
+
     pub fn regs(&self) -> String {
         use yeeboy::register::Register16::*;
         format!(
@@ -0,0 +1,156 @@
+// Shadow call stack and instruction history, to know how the CPU got somewhere.
+// Neither affects the emulation.
+
+use crate::disasm;
+use std::collections::VecDeque;
+use std::fmt;
+
+// Frames deeper than this are dropped, most likely the stack is used in a way we don't follow
+const MAX_DEPTH: usize = 256;
+
+pub const DEFAULT_HISTORY_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // Address of the CALL/RST instruction, or of the interrupted one
+    pub from: u16,
+    pub target: u16,
+    // SP after the return address was pushed
+    pub sp: u16,
+}
+
+impl Frame {
+    // How the frame was entered. E.g: "called from"
+    pub fn origin(&self) -> String {
+        match self.kind {
+            FrameKind::Call => "called from".to_string(),
+            FrameKind::Rst => "RST from".to_string(),
+            FrameKind::Interrupt(n) => format!("interrupt {} at", n),
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X} ({} {:04X})", self.target, self.origin(), self.from)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Innermost frame last
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        // Frames at or below the new SP were left without returning (stack reset, popped return address...)
+        self.frames.retain(|f| f.sp > frame.sp);
+        if self.frames.len() >= MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    // RET or RETI with the return address at `sp`
+    pub fn pop(&mut self, sp: u16) {
+        while self.frames.last().map_or(false, |f| f.sp <= sp) {
+            self.frames.pop();
+        }
+    }
+}
+
+// Ring buffer of the last executed instructions
+#[derive(Debug)]
+pub struct History {
+    entries: VecDeque<(u16, [u8; 3])>,
+    size: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_SIZE)
+    }
+}
+
+impl History {
+    pub fn new(size: usize) -> Self {
+        Self { entries: VecDeque::with_capacity(size), size }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    pub fn push(&mut self, pc: u16, bytes: [u8; 3]) {
+        if self.entries.len() >= self.size {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((pc, bytes));
+    }
+
+    // Oldest first
+    pub fn instructions(&self) -> Vec<disasm::Decoded> {
+        self.entries.iter().map(|&(pc, bytes)| {
+            disasm::decode(&|address| bytes[address.wrapping_sub(pc) as usize % 3], pc)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: FrameKind, target: u16, sp: u16) -> Frame {
+        Frame { kind, from: 0, target, sp }
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let mut stack = CallStack::new();
+        stack.push(frame(FrameKind::Call, 0x200, 0xFFFC));
+        stack.push(frame(FrameKind::Rst, 0x38, 0xFFFA));
+        stack.push(frame(FrameKind::Interrupt(0), 0x40, 0xFFF8));
+        assert_eq!(stack.frames().len(), 3);
+
+        stack.pop(0xFFF8);
+        assert_eq!(stack.frames().last().unwrap().target, 0x38);
+
+        // Returning from an outer frame drops the inner ones
+        stack.pop(0xFFFC);
+        assert!(stack.frames().is_empty());
+
+        // So does calling with the stack pointer above them
+        stack.push(frame(FrameKind::Call, 0x200, 0xFFFC));
+        stack.push(frame(FrameKind::Call, 0x300, 0xFFFA));
+        stack.push(frame(FrameKind::Call, 0x400, 0xFFFC));
+        assert_eq!(stack.frames().iter().map(|f| f.target).collect::<Vec<_>>(), vec![0x400]);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::new(2);
+        history.push(0x100, [0x00, 0x3E, 0x42]);
+        history.push(0x101, [0x3E, 0x42, 0xC3]);
+        history.push(0x103, [0xC3, 0x00, 0x01]);
+
+        let texts = history.instructions().iter().map(|d| d.text.clone()).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["ld a, $42", "jp $0100"]);
+    }
+}
@@ -1,6 +1,11 @@
 pub trait MBC {
     fn load(&self, address: u16) -> u8;
     fn store(&mut self, address: u16, value: u8);
+
+    // ROM bank currently mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> usize {
+        1
+    }
 }
 
 pub struct RomOnly {
@@ -37,6 +42,7 @@ impl MBC for RomOnly {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 enum MBC1Mode {
     RAM, ROM
 }
@@ -102,6 +108,10 @@ impl MBC for MBC1 {
             _ => panic!()
         }
     }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank as usize
+    }
 }
 
 pub struct MBC3 {
@@ -129,7 +139,7 @@ impl MBC for MBC3 {
             0x0000..=0x3FFF => self.rom[address as usize],
             0x4000..=0x7FFF => {
                 let offset = self.rom_bank * 0x4000;
-                self.rom[(offset + (address as usize & 0x3FFF)) as usize]
+                self.rom[offset + (address as usize & 0x3FFF)]
             }
             0xA000..=0xBFFF => {
                 self.ram[self.ram_bank * 0x2000 + address as usize - 0xA000]
@@ -155,6 +165,10 @@ impl MBC for MBC3 {
             _ => panic!()
         }
     }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank
+    }
 }
 
 #[derive(Debug)]
@@ -171,6 +185,12 @@ pub struct Cartridge {
 
 #[derive(Debug)]
 pub struct Headers {
+    pub title: String,
+    // Sum of the 16 title bytes, used by the CGB boot ROM to recognize games
+    pub title_checksum: u8,
+    // 0x33 means the licensee is the 2 ASCII characters of `new_licensee` instead
+    pub old_licensee: u8,
+    pub new_licensee: [u8; 2],
     pub cartridge_type: CartridgeType,
     pub rom_size: usize,
     pub ram_size: usize,
@@ -200,13 +220,34 @@ impl Headers {
         }
     }
 
-    fn new(rom: &Vec<u8>) -> Self {
+    // Title in upper case ASCII, padded with zeros. Newer cartridges use the last bytes for
+    // the manufacturer code and CGB flag, which we don't care about here.
+    fn title(rom: &[u8]) -> String {
+        rom[0x134..=0x143].iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as char)
+            .collect()
+    }
+
+    fn new(rom: &[u8]) -> Self {
         Self {
+            title: Headers::title(rom),
+            title_checksum: rom[0x134..=0x143].iter().fold(0, |sum, &c| sum.wrapping_add(c)),
+            old_licensee: rom[0x14B],
+            new_licensee: [rom[0x144], rom[0x145]],
             cartridge_type: Headers::cartridge_type(rom[0x147]),
             rom_size: Headers::rom_size(rom[0x148]),
             ram_size: Headers::ram_size(rom[0x149]),
         }
     }
+
+    pub fn is_nintendo(&self) -> bool {
+        match self.old_licensee {
+            0x01 => true,
+            0x33 => &self.new_licensee == b"01",
+            _ => false,
+        }
+    }
 }
 
 impl Cartridge {
@@ -1,34 +1,45 @@
 use crate::cartridge::Cartridge;
 use crate::cpu::Cpu;
+use crate::gpu::Layer;
 use crate::input::Button;
+use crate::palette::{ColorCorrection, Palettes, Preset};
+use crate::profiler::Profiler;
 
 pub struct Console {
     pub cpu: Cpu,
+    pub profiler: Option<Profiler>,
 }
 
 impl Console {
     pub fn new(cartridge: Cartridge, trace: bool) -> Self {
         let cpu = Cpu::new(cartridge, trace);
-        Self { cpu }
+        Self { cpu, profiler: None }
     }
 
     pub fn step(&mut self) {
+        self.cpu.memory.gpu.new_frame = false;
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.start_step(&self.cpu);
+        }
+
+        // The GPU and the timer are ticked by the CPU as it accesses memory. Dispatching an
+        // interrupt takes cycles too, they're counted with the instruction before.
         let prev_cy = self.cpu.cycles;
         self.cpu.step();
+        self.cpu.interrupt();
         let elapsed = self.cpu.cycles - prev_cy;
 
-        self.cpu.memory.gpu.step(elapsed);
-
-        if self.cpu.memory.timer.tick(elapsed) {
-            self.cpu.request_interrupt(4);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.end_step(elapsed);
         }
+    }
 
-        if self.cpu.memory.gpu.interrupts > 0 {
-            self.cpu.request_interrupt(self.cpu.memory.gpu.interrupts);
-            self.cpu.memory.gpu.interrupts = 0;
+    // Step until the CPU has run for this many cycles
+    pub fn run_until(&mut self, cycles: u64) {
+        while self.cpu.cycles < cycles {
+            self.step();
         }
-
-        self.cpu.interrupt();
     }
 
     pub fn new_frame(&self) -> bool {
@@ -39,6 +50,29 @@ impl Console {
         &self.cpu.memory.gpu.frame
     }
 
+    // Shade (0-3) of every pixel of the frame, before the output palettes are applied
+    pub fn indexed_frame(&self) -> &[u8] {
+        &self.cpu.memory.gpu.indexed_frame
+    }
+
+    // Layer (background, window or sprites) that produced every pixel of the frame
+    pub fn layers(&self) -> &[Layer] {
+        &self.cpu.memory.gpu.layers
+    }
+
+    pub fn set_palettes(&mut self, palettes: Palettes) {
+        self.cpu.memory.gpu.set_palettes(palettes);
+    }
+
+    pub fn set_palette_preset(&mut self, preset: Preset) {
+        let palettes = preset.palettes(self.cpu.memory.headers());
+        self.cpu.memory.gpu.set_palettes(palettes);
+    }
+
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        self.cpu.memory.gpu.set_color_correction(color_correction);
+    }
+
     pub fn key_down(&mut self, button: Button) {
         self.cpu.memory.input.key_down(button);
     }
@@ -47,3 +81,11 @@ impl Console {
         self.cpu.memory.input.key_up(button);
     }
 }
+
+// ROM only cartridge with the given code at the entry point, for the tests
+#[cfg(test)]
+pub(crate) fn make_console(code: &[u8]) -> Console {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + code.len()].copy_from_slice(code);
+    Console::new(Cartridge::load(rom), false)
+}
@@ -0,0 +1,131 @@
+// Code/Data Logger: records what every ROM byte was used for. The CDL file is one byte
+// of flags per ROM byte, in ROM order, so that it can be merged across sessions.
+
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const OPCODE: u8 = 0x01;
+pub const OPERAND: u8 = 0x02;
+pub const DATA: u8 = 0x04;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    // First byte of an instruction
+    Opcode,
+    // Rest of an instruction: immediate values and the second byte of CB instructions
+    Operand,
+    // Anything else the CPU reads, including OAM DMA
+    Data,
+}
+
+impl Access {
+    fn flag(self) -> u8 {
+        match self {
+            Access::Opcode => OPCODE,
+            Access::Operand => OPERAND,
+            Access::Data => DATA,
+        }
+    }
+}
+
+// Marked through a shared reference since the CPU loads memory without mutating it
+pub struct Coverage {
+    flags: Vec<Cell<u8>>,
+}
+
+impl Coverage {
+    pub fn new(rom_size: usize) -> Self {
+        Self { flags: vec![Cell::new(0); rom_size] }
+    }
+
+    // Flags from a previous session. Extra bytes are ignored, missing ones are unused.
+    pub fn from_bytes(bytes: &[u8], rom_size: usize) -> Self {
+        let coverage = Self::new(rom_size);
+        for (flags, &byte) in coverage.flags.iter().zip(bytes) {
+            flags.set(byte);
+        }
+        coverage
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, rom_size: usize) -> io::Result<Self> {
+        Ok(Self::from_bytes(&fs::read(path)?, rom_size))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.flags.iter().map(Cell::get).collect()
+    }
+
+    // Offsets past the end of the ROM wrap around, like the mappers do
+    pub fn mark(&self, offset: usize, access: Access) {
+        if self.flags.is_empty() {
+            return;
+        }
+
+        let flags = &self.flags[offset % self.flags.len()];
+        flags.set(flags.get() | access.flag());
+    }
+
+    pub fn get(&self, offset: usize) -> u8 {
+        self.flags.get(offset).map_or(0, Cell::get)
+    }
+
+    // Number of bytes used as code (opcodes and operands), as data, and never used
+    pub fn summary(&self) -> (usize, usize, usize) {
+        let flags = self.to_bytes();
+        let code = flags.iter().filter(|&&f| f & (OPCODE | OPERAND) != 0).count();
+        let data = flags.iter().filter(|&&f| f & DATA != 0).count();
+        let unused = flags.iter().filter(|&&f| f == 0).count();
+        (code, data, unused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::console::{make_console, Console};
+
+    #[test]
+    fn test_coverage() {
+        // LD A, (0150); CALL NZ, 0200 (not taken); SWAP A
+        let mut console = make_console(&[0xFA, 0x50, 0x01, 0xC4, 0x00, 0x02, 0xCB, 0x37]);
+        console.cpu.memory.enable_coverage();
+        console.cpu.registers.f = 0x80;
+        for _ in 0..3 {
+            console.step();
+        }
+
+        let coverage = console.cpu.memory.coverage.as_ref().unwrap();
+        let flags = (0x100..0x108).map(|offset| coverage.get(offset)).collect::<Vec<u8>>();
+        assert_eq!(flags, vec![OPCODE, OPERAND, OPERAND, OPCODE, OPERAND, OPERAND, OPCODE, OPERAND]);
+        assert_eq!(coverage.get(0x150), DATA);
+        assert_eq!(coverage.summary(), (8, 1, 0x8000 - 9));
+    }
+
+    #[test]
+    fn test_banks() {
+        // MBC1 with 4 banks. LD (2000), A with A = 3, then read 4000.
+        let mut rom = vec![0; 0x10000];
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        rom[0x100..0x106].copy_from_slice(&[0xEA, 0x00, 0x20, 0xFA, 0x00, 0x40]);
+        let mut console = Console::new(Cartridge::load(rom), false);
+        console.cpu.memory.enable_coverage();
+        console.cpu.registers.a = 3;
+        console.step();
+        console.step();
+
+        let coverage = console.cpu.memory.coverage.as_ref().unwrap();
+        assert_eq!(coverage.get(3 * 0x4000), DATA);
+        assert_eq!(coverage.get(0x4000), 0);
+
+        let copy = Coverage::from_bytes(&coverage.to_bytes(), 0x10000);
+        assert_eq!(copy.to_bytes(), coverage.to_bytes());
+    }
+}
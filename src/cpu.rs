@@ -1,7 +1,11 @@
+use crate::backtrace::{CallStack, Frame, FrameKind, History};
 use crate::cartridge::Cartridge;
+use crate::coverage::Access;
 use crate::memory::Memory;
 use crate::opcodes::*;
 use crate::register::{Flag, Registers, Register8, Register16, Register16::*};
+use crate::symbols::Symbols;
+use crate::trace::{TraceFormat, Tracer};
 
 static INTERRUPT_FLAG: u16 = 0xFF0F;
 static INTERRUPT_ENABLE: u16 = 0xFFFF;
@@ -91,6 +95,7 @@ pub enum Instruction {
     Sla(Storage),
     Sra(Storage),
     Srl(Storage),
+    Stop,
     Sub(Storage),
     Swap(Storage),
     // Sla(Storage),
@@ -109,8 +114,19 @@ pub struct Cpu {
     pub memory: Memory,
     pub cycles: u64,
     pub ime: bool,
+    // EI enables interrupts after the instruction following it
+    pub ime_scheduled: bool,
     pub halted: bool,
-    trace: bool,
+    // HALT with IME off and an interrupt pending: the CPU doesn't halt, and fails to increment
+    // PC after reading the next opcode, which then gets executed twice
+    pub halt_bug: bool,
+    // STOP waits for a button press. The rest of the system keeps running, so that the
+    // frontends keep presenting frames and polling inputs.
+    pub stopped: bool,
+    pub symbols: Symbols,
+    pub tracer: Option<Tracer>,
+    pub call_stack: CallStack,
+    pub history: History,
 }
 
 impl Cpu {
@@ -120,9 +136,15 @@ impl Cpu {
             registers: Registers::new(),
             pc: 0x100,
             cycles: 0,
-            ime: true,
+            ime: false,
+            ime_scheduled: false,
             halted: false,
-            trace,
+            halt_bug: false,
+            stopped: false,
+            symbols: Symbols::new(),
+            tracer: if trace { Some(Tracer::stdout(TraceFormat::Yeeboy)) } else { None },
+            call_stack: CallStack::new(),
+            history: History::default(),
             memory,
         }
     }
@@ -131,43 +153,59 @@ impl Cpu {
     // - Fetch the next opcode at PC
     // - Decode the instruction
     // - Execute the instruction
-    // - Increment the cycle count
-    #[allow(unused_variables)]
+    // Every memory access and internal delay ticks the rest of the system by one M-cycle,
+    // so the cycle count is a result of the execution.
     pub fn step(&mut self) {
-        if self.halted {
-            return self.cycles += 4;
+        if self.halted || self.stopped {
+            return self.tick();
         }
 
-        // TODO: Fix this ugly duplication. Too lazy right now
-        let cycles = match self.load_byte() {
-            0xCB => {
-                let opcode = self.load(self.pc + 1);
-                let (instruction, cycles, descr) = Self::decode_cb(opcode);
-                if self.trace { println!("{}", self.trace(descr)); }
-                self.pc += 2;
-                self.execute(instruction);
-                cycles
-            }
-            opcode => {
-                let (instruction, cycles, descr) = Self::decode(opcode);
-                if self.trace { println!("{}", self.trace(descr)); }
-                self.pc += 1;
-                self.execute(instruction);
-                cycles
-            }
+        if self.history.is_enabled() {
+            let peek = |n| self.memory.peek(self.pc.wrapping_add(n));
+            let bytes = [peek(0), peek(1), peek(2)];
+            self.history.push(self.pc, bytes);
+        }
+
+        self.trace_instruction();
+
+        if self.ime_scheduled {
+            self.ime_scheduled = false;
+            self.ime = true;
+        }
+
+        let opcode = self.fetch(self.pc, Access::Opcode);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
+
+        let instruction = match opcode {
+            0xCB => &Self::decode_cb(self.load_and_bump_pc()).0,
+            opcode => &Self::decode(opcode).0,
         };
+        self.execute(instruction);
+    }
 
-        self.cycles += cycles;
+    // Advance the rest of the system by one M-cycle
+    fn tick(&mut self) {
+        self.cycles += 4;
+        self.memory.tick();
     }
 
     // Format the current state of the CPU, registers..etc
     pub fn trace(&mut self, instruction: &'static str) -> String {
-        let flags = vec![Flag::C, Flag::H, Flag::N, Flag::Z].iter().map(|&f|
+        let flags = [Flag::C, Flag::H, Flag::N, Flag::Z].iter().map(|&f|
             if self.registers.has_flag(f) { format!("{:?}", f) } else { "-".into() }
         ).collect::<Vec<String>>().join("");
 
+        let symbol = match self.symbol(self.pc) {
+            Some(name) => format!(" <{}>", name),
+            None => String::new(),
+        };
+
         format!(
-            "AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} [{}] {:04X}: {:02X} {:02X} {:02X}  {}",
+            "AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} [{}] {:04X}: {:02X} {:02X} {:02X}  {}{}",
             self.registers.get16(AF),
             self.registers.get16(BC),
             self.registers.get16(DE),
@@ -175,13 +213,102 @@ impl Cpu {
             self.registers.get16(SP),
             flags,
             self.pc,
-            self.load(self.pc),
-            self.load(self.pc + 1),
-            self.load(self.pc + 2),
+            self.memory.peek(self.pc),
+            self.memory.peek(self.pc.wrapping_add(1)),
+            self.memory.peek(self.pc.wrapping_add(2)),
             instruction,
+            symbol,
+        )
+    }
+
+    // Same as gameboy-doctor logs
+    pub fn trace_doctor(&self) -> String {
+        let r = &self.registers;
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, self.pc,
+            self.memory.peek(self.pc),
+            self.memory.peek(self.pc.wrapping_add(1)),
+            self.memory.peek(self.pc.wrapping_add(2)),
+            self.memory.peek(self.pc.wrapping_add(3)),
+        )
+    }
+
+    fn trace_instruction(&mut self) {
+        let mut tracer = match self.tracer.take() {
+            Some(tracer) => tracer,
+            None => return,
+        };
+
+        if tracer.should_trace(self.pc, self.cycles) {
+            let line = match tracer.format {
+                TraceFormat::Yeeboy => {
+                    let descr = match self.memory.peek(self.pc) {
+                        0xCB => Self::decode_cb(self.memory.peek(self.pc.wrapping_add(1))).2,
+                        opcode => Self::decode(opcode).2,
+                    };
+                    self.trace(descr)
+                }
+                TraceFormat::Doctor => self.trace_doctor(),
+            };
+            tracer.write(&line);
+        }
+
+        self.tracer = Some(tracer);
+    }
+
+    // Call stack, innermost first, starting with the current PC
+    pub fn backtrace(&self) -> String {
+        let name = |address: u16| match self.symbol(address) {
+            Some(name) => format!(" <{}>", name),
+            None => String::new(),
+        };
+
+        let mut lines = vec![format!("#0 {:04X}{}", self.pc, name(self.pc))];
+        for (n, frame) in self.call_stack.frames().iter().rev().enumerate() {
+            lines.push(format!(
+                "#{} {:04X}{} ({} {:04X}{})",
+                n + 1, frame.target, name(frame.target), frame.origin(), frame.from, name(frame.from),
+            ));
+        }
+        lines.join("\n")
+    }
+
+    // Last executed instructions, oldest first
+    pub fn recent_instructions(&self) -> String {
+        self.history.instructions().iter().map(|decoded| {
+            match self.symbol(decoded.address) {
+                Some(name) => format!("{}  <{}>", decoded, name),
+                None => decoded.to_string(),
+            }
+        }).collect::<Vec<String>>().join("\n")
+    }
+
+    pub fn crash_report(&self, reason: &str) -> String {
+        format!(
+            "{}\n\nRegisters: AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} PC:{:04X}\n\nBacktrace:\n{}\n\nLast instructions:\n{}",
+            reason,
+            self.registers.get16(AF),
+            self.registers.get16(BC),
+            self.registers.get16(DE),
+            self.registers.get16(HL),
+            self.registers.sp,
+            self.pc,
+            self.backtrace(),
+            self.recent_instructions(),
         )
     }
 
+    fn crash(&self, reason: &str) -> ! {
+        panic!("{}", self.crash_report(reason))
+    }
+
+    // Name of an address from the loaded symbols, using the ROM bank currently mapped there
+    pub fn symbol(&self, address: u16) -> Option<String> {
+        let bank = self.memory.rom_bank(address).unwrap_or(0);
+        self.symbols.describe(bank, address)
+    }
+
     pub fn decode(opcode: u8) -> &'static (Instruction, u64, &'static str) {
         OPCODES.get(opcode as usize).unwrap()
     }
@@ -207,9 +334,11 @@ impl Cpu {
             },
             AddSpE8 => {
                 let a = self.registers.sp as i32;
-                let b = self.load_and_bump_pc() as i8 as i8;
+                let b = self.load_and_bump_pc() as i8;
                 let bb = b as i32;
                 let result = a.wrapping_add(bb);
+                self.tick();
+                self.tick();
                 self.registers.sp = result as u16;
                 let h = ((a & 0xF) + (bb & 0xF)) > 0xF;
                 let c = ((a & 0xFF) + (bb & 0xFF)) > 0xFF;
@@ -232,6 +361,7 @@ impl Cpu {
                 let a = self.registers.get16(r) as u32;
                 let result = hl + a;
                 let h = (hl & 0xFFF) + (a & 0xFFF) > 0xFFF;
+                self.tick();
                 self.registers.set16(HL, (result & 0xFFFF) as u16);
                 self.registers.flag(Flag::H, h);
                 self.registers.flag(Flag::N, false);
@@ -251,7 +381,7 @@ impl Cpu {
                 if self.registers.has_flag(flag) == cond {
                     self.do_call();
                 } else {
-                    self.pc += 2;
+                    self.load_word_and_bump_pc();
                 }
             },
             Ccf => {
@@ -278,6 +408,7 @@ impl Cpu {
                 self.registers.flag(Flag::N, true);
             },
             Dec16(r) => {
+                self.tick();
                 let value = self.registers.get16(r).wrapping_sub(1);
                 self.registers.set16(r, value);
             },
@@ -303,9 +434,18 @@ impl Cpu {
                 self.registers.flag(Flag::Z, total == 0);
                 self.registers.flag(Flag::C, result & 0x60 != 0);
             }
-            Di => self.ime = false,
-            Ei => self.ime = true,
-            Halt => self.halted = true,
+            Di => {
+                self.ime = false;
+                self.ime_scheduled = false;
+            },
+            Ei => self.ime_scheduled = true,
+            Halt => {
+                if !self.ime && self.has_interrupt() {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+            },
             Inc(s @ Storage::Pointer(HL)) => {
                 let a = s.load(self);
                 let result = a.wrapping_add(1);
@@ -323,17 +463,19 @@ impl Cpu {
                 self.registers.flag(Flag::N, false);
             },
             Inc16(r) => {
+                self.tick();
                 let result = self.registers.get16(r).wrapping_add(1);
                 self.registers.set16(r, result);
             }
             Jp => {
                 self.pc = self.load_word();
+                self.tick();
             },
             JpCond(flag, cond) => {
                 let address = self.load_word_and_bump_pc();
                 if self.registers.has_flag(flag) == cond {
                     self.pc = address;
-                    self.cycles += 4;
+                    self.tick();
                 }
             },
             JpHl => {
@@ -343,12 +485,13 @@ impl Cpu {
                 let offset = self.load_and_bump_pc() as i8;
                 if self.registers.has_flag(flag) == cond {
                     self.pc = (self.pc as u32 as i32).wrapping_add(offset as i32) as u16;
-                    self.cycles += 4;
+                    self.tick();
                 }
             },
             JrE8 => {
                 let offset = self.load_and_bump_pc() as i8;
                 self.pc = (self.pc as u32 as i32).wrapping_add(offset as i32) as u16;
+                self.tick();
             },
             Lda16A => {
                 let address = self.load_word_and_bump_pc();
@@ -356,7 +499,9 @@ impl Cpu {
             },
             Lda16Sp => {
                 let address = self.load_word_and_bump_pc();
-                self.memory.store16(address, self.registers.sp);
+                let [lo, hi] = self.registers.sp.to_le_bytes();
+                self.store(address, lo);
+                self.store(address.wrapping_add(1), hi);
             },
             LdAA16 => {
                 let address = self.load_word_and_bump_pc();
@@ -383,9 +528,10 @@ impl Cpu {
             },
             LdHlSpE8 => {
                 let a = self.registers.sp as i32;
-                let b = self.load_and_bump_pc() as i8 as i8;
+                let b = self.load_and_bump_pc() as i8;
                 let bb = b as i32;
                 let result = a.wrapping_add(bb);
+                self.tick();
                 self.registers.set16(HL, result as u16);
                 let h = ((a & 0xF) + (bb & 0xF)) > 0xF;
                 let c = ((a & 0xFF) + (bb & 0xFF)) > 0xFF;
@@ -396,7 +542,8 @@ impl Cpu {
             }
             LdRHl(r) => {
                 let address = self.registers.get16(HL);
-                self.registers.set(r, self.load(address));
+                let value = self.load(address);
+                self.registers.set(r, value);
             },
             LdiAHl => {
                 let address = self.registers.get16(HL);
@@ -441,6 +588,7 @@ impl Cpu {
                 self.registers.sp = self.load_word_and_bump_pc();
             },
             LdSpHl => {
+                self.tick();
                 self.registers.sp = self.registers.get16(HL);
             },
             LdWriteIoC => {
@@ -458,47 +606,32 @@ impl Cpu {
                 self.do_or(value);
             },
             Pop16(AF) => {
-                let af = self.memory.load16(self.registers.sp) & 0xFFF0;
-                let sp = self.registers.sp.wrapping_add(2);
+                let af = self.pop16() & 0xFFF0;
                 self.registers.set16(AF, af);
-                self.registers.set16(SP, sp);
             },
             Pop16(r) => {
-                let value = self.memory.load16(self.registers.sp);
-                let sp = self.registers.sp.wrapping_add(2);
+                let value = self.pop16();
                 self.registers.set16(r, value);
-                self.registers.sp = sp;
             },
             Push16(r) => {
-                let sp = self.registers.sp.wrapping_sub(2);
-                self.memory.store16(sp, self.registers.get16(r));
-                self.registers.sp = sp;
+                self.tick();
+                self.push16(self.registers.get16(r));
             },
             Res(bit, s) => {
                 let a = s.load(self);
                 let result = a & !(1 << bit);
                 s.store(self, result);
             }
-            Ret => {
-                let sp = self.registers.sp;
-                let pc = self.memory.load16(sp);
-                self.registers.sp = sp.wrapping_add(2);
-                self.pc = pc;
-            },
+            Ret => self.do_ret(),
             Reti => {
-                let sp = self.registers.sp;
-                let pc = self.memory.load16(sp);
-                self.registers.sp = sp.wrapping_add(2);
-                self.pc = pc;
+                self.do_ret();
                 self.ime = true;
             },
             RetCond(flag, cond) => {
+                // Checking the condition takes an extra M-cycle
+                self.tick();
                 if self.registers.has_flag(flag) == cond {
-                    let sp = self.registers.sp;
-                    let pc = self.memory.load16(sp);
-                    self.registers.sp = sp.wrapping_add(2);
-                    self.pc = pc;
-                    self.cycles += 12;
+                    self.do_ret();
                 }
             },
             Rl(s) => {
@@ -586,8 +719,9 @@ impl Cpu {
             },
             Rst(n) => {
                 let sp = self.registers.sp.wrapping_sub(2);
-                self.memory.store16(sp, self.pc);
-                self.registers.sp = sp;
+                self.call_stack.push(Frame { kind: FrameKind::Rst, from: self.pc.wrapping_sub(1), target: n, sp });
+                self.tick();
+                self.push16(self.pc);
                 self.pc = n;
             }
             Sbc(s) => {
@@ -631,6 +765,11 @@ impl Cpu {
                 self.registers.flag(Flag::H, false);
                 self.registers.flag(Flag::C, a & 1 == 1);
             },
+            Stop => {
+                // The byte after STOP is skipped
+                self.pc = self.pc.wrapping_add(1);
+                self.stopped = true;
+            },
             Sub(s) => {
                 let a = self.registers.a;
                 let b = s.load(self);
@@ -670,22 +809,24 @@ impl Cpu {
                 self.registers.flag(Flag::C, false);
             },
             NOP => {},
-            Undefined => panic!("Executing undefined instruction at {:04X}", self.pc),
+            Undefined => self.crash(&format!("Executing undefined instruction at {:04X}", self.pc)),
             _ => {
-                let opcode = self.load(self.pc - 1);
-                panic!("Reached unimplemented instruction: Opcode {:02X} @ {:04X}", opcode, self.pc)
+                let opcode = self.memory.peek(self.pc - 1);
+                self.crash(&format!("Reached unimplemented instruction: Opcode {:02X} @ {:04X}", opcode, self.pc))
             },
         }
     }
 
     // Load the byte at current PC
     fn load_byte(&mut self) -> u8 {
-        self.load(self.pc)
+        self.fetch(self.pc, Access::Operand)
     }
 
     // Load the word at current PC
     fn load_word(&mut self) -> u16 {
-        self.memory.load16(self.pc)
+        let lo = self.fetch(self.pc, Access::Operand) as u16;
+        let hi = self.fetch(self.pc.wrapping_add(1), Access::Operand) as u16;
+        hi << 8 | lo
     }
 
     // Load the byte at current PC and increment PC by 1
@@ -701,40 +842,52 @@ impl Cpu {
         word
     }
 
-    fn load(&self, address: u16) -> u8 {
+    // Memory accesses take one M-cycle each. The access happens at the end of it.
+    fn fetch(&mut self, address: u16, access: Access) -> u8 {
+        self.tick();
+        self.memory.fetch(address, access)
+    }
+
+    fn load(&mut self, address: u16) -> u8 {
+        self.tick();
         self.memory.load(address)
     }
 
     fn store(&mut self, address: u16, value: u8) {
-        if address == 0xFF46 {
-            return self.oam_dma(value);
-        }
-
+        self.tick();
         self.memory.store(address, value);
     }
 
+    // High byte first, like the hardware
     fn push16(&mut self, value: u16) {
-        let sp = self.registers.sp.wrapping_sub(2);
-        self.memory.store16(sp, value);
-        self.registers.sp = sp;
+        let [lo, hi] = value.to_le_bytes();
+        let sp = self.registers.sp;
+        self.store(sp.wrapping_sub(1), hi);
+        self.store(sp.wrapping_sub(2), lo);
+        self.registers.sp = sp.wrapping_sub(2);
     }
 
-    fn oam_dma(&mut self, value: u8) {
-        let start = (value as u16) << 8;
-        for offset in 0..=0x9F {
-            let byte = self.load(start + offset);
-            self.memory.store(0xFE00 + offset, byte);
-        }
-        self.cycles += 160;
+    fn pop16(&mut self) -> u16 {
+        let sp = self.registers.sp;
+        let lo = self.load(sp);
+        let hi = self.load(sp.wrapping_add(1));
+        self.registers.sp = sp.wrapping_add(2);
+        u16::from_le_bytes([lo, hi])
     }
 
     fn do_call(&mut self) {
         let address = self.load_word();
         let sp = self.registers.sp.wrapping_sub(2);
-        self.memory.store16(sp, self.pc + 2);
-        self.registers.sp = sp;
+        self.call_stack.push(Frame { kind: FrameKind::Call, from: self.pc.wrapping_sub(1), target: address, sp });
+        self.tick();
+        self.push16(self.pc + 2);
         self.pc = address;
-        self.cycles += 12;
+    }
+
+    fn do_ret(&mut self) {
+        self.call_stack.pop(self.registers.sp);
+        self.pc = self.pop16();
+        self.tick();
     }
 
     fn do_adc(&mut self, value: u16) {
@@ -791,21 +944,21 @@ impl Cpu {
     }
 
     pub fn is_interrupt_enabled(&mut self, n: u8) -> bool {
-        self.load(INTERRUPT_ENABLE) & (1 << n) != 0
+        self.memory.peek(INTERRUPT_ENABLE) & (1 << n) != 0
     }
 
     pub fn is_interrupt_requested(&mut self, n: u8) -> bool {
-        self.load(INTERRUPT_FLAG) & (1 << n) != 0
+        self.memory.peek(INTERRUPT_FLAG) & (1 << n) != 0
     }
 
     pub fn request_interrupt(&mut self, n: u8) {
-        let isf = self.load(INTERRUPT_FLAG);
-        self.store(INTERRUPT_FLAG, isf | n);
+        let isf = self.memory.peek(INTERRUPT_FLAG);
+        self.memory.poke(INTERRUPT_FLAG, isf | n);
     }
 
     pub fn has_interrupt(&mut self) -> bool {
-        let interrupt_flag = self.load(INTERRUPT_FLAG);
-        let interrupt_enable = self.load(INTERRUPT_ENABLE);
+        let interrupt_flag = self.memory.peek(INTERRUPT_FLAG);
+        let interrupt_enable = self.memory.peek(INTERRUPT_ENABLE);
         interrupt_flag & interrupt_enable != 0
     }
 
@@ -815,32 +968,41 @@ impl Cpu {
             self.halted = false;
         }
 
+        // Joypad lines going low wake up from STOP, whether the interrupt is enabled or not
+        if self.is_interrupt_requested(4) {
+            self.stopped = false;
+        }
+
         if self.ime {
             self.check_interrupts(0);
         }
     }
 
     fn check_interrupts(&mut self, n: u8) {
-        // We only handle:
         // 0 - VBLank
         // 1 - LCD STAT
         // 2 - Timer
-        // TODO: Serial (3)
-        // TODO: Joypad (4)
-        if n > 3 { return };
+        // 3 - Serial
+        // 4 - Joypad
+        if n > 4 { return };
 
-        let if_val = self.load(INTERRUPT_FLAG);
+        let if_val = self.memory.peek(INTERRUPT_FLAG);
 
         if if_val & (1 << n) != 0 && self.is_interrupt_enabled(n) {
             // Unset the interrupt in IF
             let if_val = if_val & !(1 << n);
-            self.store(INTERRUPT_FLAG, if_val);
-            // Push the current PC
+            self.memory.poke(INTERRUPT_FLAG, if_val);
+            // Two idle M-cycles, then push the current PC
+            self.tick();
+            self.tick();
             self.push16(self.pc);
+            let frame = Frame { kind: FrameKind::Interrupt(n), from: self.pc, target: INTERRUPT_VECTORS[n as usize], sp: self.registers.sp };
+            self.call_stack.push(frame);
             // Disable IME
             self.ime = false;
-            // Jump to the appropriate interrupt vector
+            // Jump to the appropriate interrupt vector, which takes one more M-cycle
             self.pc = INTERRUPT_VECTORS[n as usize];
+            self.tick();
         } else {
             self.check_interrupts(n + 1);
         }
@@ -875,29 +1037,35 @@ impl Storage {
 
 #[cfg(test)]
 mod tests {
-    use crate::cpu::Cpu;
+    use crate::cpu::{Cpu, Instruction::*};
     use crate::cartridge::{Headers, Cartridge, CartridgeType, RomOnly};
+    use crate::input::Button;
     use crate::register::Flag;
+    use crate::symbols::Symbols;
 
     fn make_cpu() -> Cpu {
-        let headers = Headers { cartridge_type: CartridgeType::RomOnly, rom_size: 0, ram_size: 0 };
-        let mbc = RomOnly::new(vec![0; 0x2000]);
+        make_cpu_with(&[])
+    }
+
+    // ROM is read-only, so programs go in before the cartridge is made, at the entry point
+    fn make_cpu_with(program: &[u8]) -> Cpu {
+        let mut rom = vec![0; 0x2000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let headers = Headers { title: String::new(), title_checksum: 0, old_licensee: 0, new_licensee: [0; 2], cartridge_type: CartridgeType::RomOnly, rom_size: 0, ram_size: 0 };
+        let mbc = RomOnly::new(rom);
         let cart = Cartridge { mbc: Box::new(mbc), headers };
         Cpu::new(cart, false)
     }
 
     #[test]
     fn test_load_byte() {
-        let mut cpu = make_cpu();
-        cpu.memory.store(cpu.pc, 0x42);
+        let mut cpu = make_cpu_with(&[0x42]);
         assert_eq!(cpu.load_byte(), 0x42);
     }
 
     #[test]
     fn test_load_word() {
-        let mut cpu = make_cpu();
-        cpu.memory.store(cpu.pc, 0x42);
-        cpu.memory.store(cpu.pc + 1, 0x12);
+        let mut cpu = make_cpu_with(&[0x42, 0x12]);
         assert_eq!(cpu.load_word(), 0x1242);
     }
 
@@ -905,17 +1073,15 @@ mod tests {
     #[test]
     fn test_load_and_bump_pc() {
         let mut cpu = make_cpu();
-        cpu.pc = 0x1234;
+        cpu.pc = 0xC234;
         cpu.memory.store(cpu.pc, 0x42);
         assert_eq!(cpu.load_and_bump_pc(), 0x42);
-        assert_eq!(cpu.pc, 0x1235);
+        assert_eq!(cpu.pc, 0xC235);
     }
 
     #[test]
     fn test_ld_n() {
-        let mut cpu = make_cpu();
-        cpu.memory.store(cpu.pc, 0x06);
-        cpu.memory.store(cpu.pc + 1, 0x42);
+        let mut cpu = make_cpu_with(&[0x06, 0x42]);
         cpu.step();
         assert_eq!(cpu.registers.b, 0x42);
         assert_eq!(cpu.cycles, 8);
@@ -924,10 +1090,7 @@ mod tests {
 
     #[test]
     fn test_ld_nn() {
-        let mut cpu = make_cpu();
-        cpu.memory.store(cpu.pc, 0x01);
-        cpu.memory.store(cpu.pc + 1, 0x34);
-        cpu.memory.store(cpu.pc + 2, 0x12);
+        let mut cpu = make_cpu_with(&[0x01, 0x34, 0x12]);
         cpu.step();
         assert_eq!(cpu.registers.b, 0x12);
         assert_eq!(cpu.registers.c, 0x34);
@@ -937,10 +1100,9 @@ mod tests {
 
     #[test]
     fn test_inc() {
-        let mut cpu = make_cpu();
+        let mut cpu = make_cpu_with(&[0x04]);
         cpu.registers.b = 0x12;
         cpu.registers.flag(Flag::N, true);
-        cpu.memory.store(cpu.pc, 0x04);
         cpu.step();
         assert_eq!(cpu.registers.b, 0x13);
         assert!(!cpu.registers.has_flag(Flag::N));
@@ -949,20 +1111,18 @@ mod tests {
 
     #[test]
     fn test_ld_rr() {
-        let mut cpu = make_cpu();
+        let mut cpu = make_cpu_with(&[0x47]);
         cpu.registers.a = 0x43;
         cpu.registers.b = 0x12;
-        cpu.memory.store(cpu.pc, 0x47);
         cpu.step();
         assert_eq!(cpu.registers.b, 0x43);
     }
 
     #[test]
     fn test_dec() {
-        let mut cpu = make_cpu();
+        let mut cpu = make_cpu_with(&[0x05]);
         cpu.registers.b = 0x4;
         cpu.registers.flag(Flag::N, false);
-        cpu.memory.store(cpu.pc, 0x05);
         cpu.step();
         assert_eq!(cpu.registers.b, 0x3);
         assert!(cpu.registers.has_flag(Flag::N));
@@ -971,9 +1131,7 @@ mod tests {
 
     #[test]
     fn test_jp() {
-        let mut cpu = make_cpu();
-        cpu.memory.store(cpu.pc, 0xC3);
-        cpu.memory.store16(cpu.pc + 1, 0x1234);
+        let mut cpu = make_cpu_with(&[0xC3, 0x34, 0x12]);
         cpu.step();
         assert_eq!(cpu.pc, 0x1234);
         assert_eq!(cpu.cycles, 16);
@@ -981,26 +1139,21 @@ mod tests {
 
     #[test]
     fn jr() {
-        let mut cpu = make_cpu();
-        cpu.memory.store(cpu.pc, 0x20);
-        cpu.memory.store(cpu.pc + 1, -5 as i8 as u8);
+        let mut cpu = make_cpu_with(&[0x20, -5_i8 as u8]);
         cpu.registers.flag(Flag::Z, false);
         cpu.step();
         // +2 bytes for the instruction and the operand
         assert_eq!(cpu.pc, 0x100 + 2 - 5);
         assert_eq!(cpu.cycles, 12);
 
-        let mut cpu = make_cpu();
-        cpu.memory.store(cpu.pc, 0x20);
-        cpu.memory.store(cpu.pc + 1, 5);
+        let mut cpu = make_cpu_with(&[0x20, 5]);
         cpu.registers.flag(Flag::Z, false);
         cpu.step();
         // +2 bytes for the instruction and the operand
         assert_eq!(cpu.pc, 0x100 + 2 + 5);
         assert_eq!(cpu.cycles, 12);
 
-        let mut cpu = make_cpu();
-        cpu.memory.store(cpu.pc, 0x20);
+        let mut cpu = make_cpu_with(&[0x20]);
         cpu.registers.flag(Flag::Z, true);
         cpu.step();
         assert_eq!(cpu.pc, 0x102);
@@ -1009,9 +1162,223 @@ mod tests {
 
     #[test]
     fn test_nop() {
-        let mut cpu = make_cpu();
-        cpu.memory.store(cpu.pc, 0x00);
+        let mut cpu = make_cpu_with(&[0x00]);
         cpu.step();
         assert_eq!(cpu.cycles, 4);
     }
+
+    // Every instruction against the cycles in the opcode tables, with the branches not taken
+    #[test]
+    fn test_instruction_timing() {
+        let timed = |bytes: &[u8], flags: u8| {
+            let mut cpu = make_cpu_with(bytes);
+            cpu.registers.f = flags;
+            cpu.step();
+            cpu.cycles
+        };
+
+        for opcode in 0..=0xFF {
+            let (instruction, cycles, descr) = Cpu::decode(opcode);
+            let flags = match instruction {
+                NotImplemented | Undefined | Halt => continue,
+                CallCond(Flag::Z, cond) | JpCond(Flag::Z, cond) | Jr(Flag::Z, cond) | RetCond(Flag::Z, cond) => if *cond { 0x00 } else { 0x80 },
+                CallCond(_, cond) | JpCond(_, cond) | Jr(_, cond) | RetCond(_, cond) => if *cond { 0x00 } else { 0x10 },
+                _ => 0x00,
+            };
+            assert_eq!(timed(&[opcode], flags), *cycles, "{:02X} {}", opcode, descr);
+        }
+
+        for opcode in 0..=0xFF {
+            let (_, cycles, descr) = Cpu::decode_cb(opcode);
+            assert_eq!(timed(&[0xCB, opcode], 0), *cycles, "CB {:02X} {}", opcode, descr);
+        }
+
+        // Taken branches
+        assert_eq!(timed(&[0xC2], 0x00), 16);
+        assert_eq!(timed(&[0xC4], 0x00), 24);
+        assert_eq!(timed(&[0xC0], 0x00), 20);
+    }
+
+    // Accesses happen on their own M-cycle, not at the end of the instruction
+    #[test]
+    fn test_memory_timing() {
+        // LD A, (FF05) reads TIMA on its 4th M-cycle, LDH A, (C) on its 2nd
+        for (bytes, tima) in [(&[0xFA, 0x05, 0xFF][..], 1), (&[0xF2][..], 0)] {
+            let mut cpu = make_cpu_with(bytes);
+            // TIMA increments every 16 cycles
+            cpu.memory.timer.tac = 0x05;
+            cpu.registers.c = 0x05;
+            cpu.step();
+            assert_eq!(cpu.registers.a, tima);
+        }
+    }
+
+    #[test]
+    fn test_interrupt_timing() {
+        let mut cpu = make_cpu();
+        cpu.ime = true;
+        cpu.memory.poke(0xFFFF, 1);
+        cpu.request_interrupt(1);
+        cpu.interrupt();
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(cpu.cycles, 20);
+        assert_eq!(cpu.memory.load16(0xFFFC), 0x100);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        // LD A, C0; LDH (46), A
+        let mut cpu = make_cpu_with(&[0x3E, 0xC0, 0xE0, 0x46]);
+        for offset in 0..0xA0 {
+            cpu.memory.store(0xC000 + offset, offset as u8 + 1);
+        }
+        cpu.step();
+        cpu.step();
+
+        // One byte per M-cycle, and OAM reads as FF in the meantime
+        assert_eq!(cpu.memory.load(0xFE00), 0xFF);
+        for _ in 0..0x9F {
+            cpu.tick();
+        }
+        assert_eq!(cpu.memory.load(0xFE9F), 0xFF);
+        cpu.tick();
+        assert_eq!(cpu.memory.load(0xFE00), 0x01);
+        assert_eq!(cpu.memory.load(0xFE9F), 0xA0);
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        // EI; NOP; NOP with a VBlank interrupt pending
+        let mut cpu = make_cpu_with(&[0xFB, 0x00, 0x00]);
+        assert!(!cpu.ime);
+        cpu.memory.poke(0xFFFF, 1);
+        cpu.request_interrupt(1);
+
+        cpu.step();
+        cpu.interrupt();
+        assert_eq!(cpu.pc, 0x101);
+        cpu.step();
+        cpu.interrupt();
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(cpu.memory.load16(cpu.registers.sp), 0x102);
+
+        // EI; DI never enables interrupts
+        let mut cpu = make_cpu_with(&[0xFB, 0xF3, 0x00]);
+        cpu.memory.poke(0xFFFF, 1);
+        cpu.request_interrupt(1);
+        for _ in 0..3 {
+            cpu.step();
+            cpu.interrupt();
+        }
+        assert!(!cpu.ime);
+        assert_eq!(cpu.pc, 0x103);
+    }
+
+    #[test]
+    fn test_halt() {
+        // HALT; INC A. Without a pending interrupt, halts until one is requested, even with IME off.
+        let mut cpu = make_cpu_with(&[0x76, 0x3C]);
+        cpu.memory.poke(0xFFFF, 1);
+        cpu.step();
+        cpu.step();
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x101);
+
+        cpu.request_interrupt(1);
+        cpu.interrupt();
+        assert!(!cpu.halted);
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x02);
+        assert_eq!(cpu.pc, 0x102);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        // HALT; INC A with IME off and an interrupt pending: INC A runs twice
+        let mut cpu = make_cpu_with(&[0x76, 0x3C]);
+        cpu.memory.poke(0xFFFF, 1);
+        cpu.request_interrupt(1);
+        cpu.step();
+        assert!(!cpu.halted);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x101);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x102);
+        assert_eq!(cpu.registers.a, 0x03);
+    }
+
+    #[test]
+    fn test_stop() {
+        // STOP; then INC A once a button is pressed
+        let mut cpu = make_cpu_with(&[0x10, 0x00, 0x3C]);
+        cpu.memory.store(0xFF00, 0x10);
+        cpu.step();
+        cpu.step();
+        assert!(cpu.stopped);
+        assert_eq!(cpu.pc, 0x102);
+
+        cpu.memory.input.key_down(Button::Start);
+        cpu.step();
+        cpu.interrupt();
+        assert!(!cpu.stopped);
+        cpu.step();
+        assert_eq!(cpu.registers.a, 0x02);
+    }
+
+    #[test]
+    fn test_joypad_interrupt() {
+        let mut cpu = make_cpu();
+        cpu.ime = true;
+        cpu.memory.poke(0xFFFF, 0x10);
+        cpu.memory.store(0xFF00, 0x20);
+        cpu.memory.input.key_down(Button::Down);
+        cpu.step();
+        cpu.interrupt();
+        assert_eq!(cpu.pc, 0x60);
+    }
+
+    #[test]
+    fn test_trace_symbols() {
+        let mut cpu = make_cpu();
+        assert!(cpu.trace("NOP").ends_with("0100: 00 00 00  NOP"));
+
+        cpu.symbols = Symbols::parse("00:00FE Init\n");
+        assert!(cpu.trace("NOP").ends_with("NOP <Init+$2>"));
+    }
+
+    #[test]
+    fn test_call_stack() {
+        // CALL 0110; at 0110: RET
+        let mut program = vec![0; 0x11];
+        program[..3].copy_from_slice(&[0xCD, 0x10, 0x01]);
+        program[0x10] = 0xC9;
+        let mut cpu = make_cpu_with(&program);
+
+        cpu.step();
+        assert_eq!(cpu.call_stack.frames().len(), 1);
+        assert_eq!(cpu.call_stack.frames()[0].from, 0x100);
+
+        // VBlank interrupt
+        cpu.ime = true;
+        cpu.memory.poke(0xFFFF, 1);
+        cpu.request_interrupt(1);
+        cpu.interrupt();
+        assert_eq!(cpu.call_stack.frames().len(), 2);
+        assert_eq!(cpu.backtrace(), "#0 0040\n#1 0040 (interrupt 0 at 0110)\n#2 0110 (called from 0100)");
+
+        cpu.pc = 0x110;
+        cpu.step();
+        assert_eq!(cpu.call_stack.frames().len(), 1);
+        cpu.step();
+        assert!(cpu.call_stack.frames().is_empty());
+        assert_eq!(cpu.pc, 0x103);
+    }
+
+    #[test]
+    #[should_panic(expected = "Last instructions:\n0100: 00        nop\n0101: D3        db $D3")]
+    fn test_crash_report() {
+        let mut cpu = make_cpu_with(&[0x00, 0xD3]);
+        cpu.step();
+        cpu.step();
+    }
 }
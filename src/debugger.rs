@@ -0,0 +1,553 @@
+use crate::backtrace::Frame;
+use crate::console::Console;
+use crate::cpu::{Cpu, Instruction};
+use crate::memory::{Watchpoint, WatchHit};
+use crate::register::Register16;
+use crate::symbols::Symbols;
+use std::fmt;
+
+// Break when PC reaches an address. If a bank is given, the breakpoint only
+// triggers when that ROM bank is mapped at the address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub bank: Option<usize>,
+    pub address: u16,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Self {
+        Self { bank: None, address }
+    }
+
+    pub fn with_bank(bank: usize, address: u16) -> Self {
+        Self { bank: Some(bank), address }
+    }
+
+    // Parse "addr" or "bank:addr", both in hex. E.g: "0150" or "01:4000"
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.find(':') {
+            Some(idx) => {
+                let bank = usize::from_str_radix(&s[..idx], 16).ok()?;
+                let address = parse_hex(&s[idx + 1..])?;
+                Some(Self::with_bank(bank, address))
+            }
+            None => parse_hex(s).map(Self::new),
+        }
+    }
+
+    // Like `parse`, but symbol names are tried first. E.g: "Main.loop"
+    pub fn parse_with_symbols(s: &str, symbols: &Symbols) -> Option<Self> {
+        match symbols.address(s) {
+            Some((bank, address)) if (0x4000..0x8000).contains(&address) => Some(Self::with_bank(bank, address)),
+            Some((_, address)) => Some(Self::new(address)),
+            None => Self::parse(s),
+        }
+    }
+
+    fn matches(&self, console: &Console) -> bool {
+        let pc = console.cpu.pc;
+        self.address == pc
+            && (self.bank.is_none() || self.bank == console.cpu.memory.rom_bank(pc))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.address),
+            None => write!(f, "{:04X}", self.address),
+        }
+    }
+}
+
+// What to do when resuming execution
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Continue,
+    Step,
+    StepOver,
+    StepOut,
+    RunToFrame,
+}
+
+// Why the execution stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    Breakpoint(Breakpoint),
+    Watchpoint(WatchHit),
+    Step,
+    Frame,
+    Interrupted,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Breakpoint(b) => write!(f, "Breakpoint {}", b),
+            Stop::Watchpoint(w) if w.write => write!(f, "Watchpoint: wrote {:02X} @ {:04X}", w.value, w.address),
+            Stop::Watchpoint(w) => write!(f, "Watchpoint: read {:02X} @ {:04X}", w.value, w.address),
+            Stop::Step => write!(f, "Step"),
+            Stop::Frame => write!(f, "Frame"),
+            Stop::Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
+// Execution state between two calls to `tick`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Paused,
+    Continue,
+    Step,
+    // Run until PC reaches the return address with the stack back to where it was
+    StepOver { pc: u16, sp: u16 },
+    // Run until the current frame of the shadow call stack returns
+    StepOut { depth: usize },
+    RunToFrame,
+}
+
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    state: State,
+    // Don't break on the breakpoint we're resuming from
+    resuming: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: vec![],
+            state: State::Paused,
+            resuming: false,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state == State::Paused
+    }
+
+    pub fn pause(&mut self) {
+        self.state = State::Paused;
+    }
+
+    pub fn resume(&mut self, console: &Console, action: Action) {
+        let cpu = &console.cpu;
+        self.resuming = true;
+        self.state = match action {
+            Action::Continue => State::Continue,
+            Action::Step => State::Step,
+            Action::StepOver => match Self::call_length(cpu) {
+                Some(length) => State::StepOver { pc: cpu.pc.wrapping_add(length), sp: cpu.registers.sp },
+                None => State::Step,
+            },
+            Action::StepOut => State::StepOut { depth: cpu.call_stack.frames().len() },
+            Action::RunToFrame => State::RunToFrame,
+        };
+    }
+
+    // Run a single console step according to the current state.
+    // Returns the reason why the execution stopped, if it did.
+    pub fn tick(&mut self, console: &mut Console) -> Option<Stop> {
+        if self.is_paused() {
+            return None;
+        }
+
+        if !self.resuming {
+            if let Some(&b) = self.breakpoints.iter().find(|b| b.matches(console)) {
+                return self.stop(Stop::Breakpoint(b));
+            }
+        }
+        self.resuming = false;
+
+        // A return from the frame we're stepping out of. Needed when that frame isn't tracked,
+        // e.g. when stepping out of the code the console started with.
+        let pc = console.cpu.pc;
+        let returning = match self.state {
+            State::StepOut { depth } => Self::is_return(&console.cpu) && console.cpu.call_stack.frames().len() <= depth,
+            _ => false,
+        };
+
+        console.step();
+
+        if let Some(hit) = console.cpu.memory.watch_hit.take() {
+            return self.stop(Stop::Watchpoint(hit));
+        }
+
+        let cpu = &console.cpu;
+        match self.state {
+            State::Step => self.stop(Stop::Step),
+            State::StepOver { pc, sp } if cpu.pc == pc && cpu.registers.sp >= sp => self.stop(Stop::Step),
+            State::StepOut { depth } if cpu.call_stack.frames().len() < depth => self.stop(Stop::Step),
+            // Not taken if it's a conditional return that fell through
+            State::StepOut { .. } if returning && cpu.pc != pc.wrapping_add(1) => self.stop(Stop::Step),
+            State::RunToFrame if console.new_frame() => self.stop(Stop::Frame),
+            _ => None,
+        }
+    }
+
+    // Resume and run until the execution stops, or at most `max_steps` console steps
+    pub fn run(&mut self, console: &mut Console, action: Action, max_steps: usize) -> Option<Stop> {
+        self.resume(console, action);
+        for _ in 0..max_steps {
+            if let Some(stop) = self.tick(console) {
+                return Some(stop);
+            }
+        }
+        self.pause();
+        None
+    }
+
+    fn stop(&mut self, stop: Stop) -> Option<Stop> {
+        self.state = State::Paused;
+        Some(stop)
+    }
+
+    // Length of the instruction at PC if it's a call, so that we know where it returns
+    fn call_length(cpu: &Cpu) -> Option<u16> {
+        let opcode = cpu.memory.peek(cpu.pc);
+        match Cpu::decode(opcode).0 {
+            Instruction::Call | Instruction::CallCond(..) => Some(3),
+            Instruction::Rst(_) => Some(1),
+            _ => None,
+        }
+    }
+
+    fn is_return(cpu: &Cpu) -> bool {
+        let opcode = cpu.memory.peek(cpu.pc);
+        matches!(Cpu::decode(opcode).0, Instruction::Ret | Instruction::Reti | Instruction::RetCond(..))
+    }
+
+    pub fn add_watchpoint(&mut self, console: &mut Console, watchpoint: Watchpoint) {
+        console.cpu.memory.watchpoints.push(watchpoint);
+    }
+
+    pub fn set_register(&mut self, console: &mut Console, name: &str, value: u16) -> Result<(), String> {
+        let cpu = &mut console.cpu;
+        let registers = &mut cpu.registers;
+        match name.to_lowercase().as_str() {
+            "a" => registers.a = value as u8,
+            "f" => registers.f = value as u8 & 0xF0,
+            "b" => registers.b = value as u8,
+            "c" => registers.c = value as u8,
+            "d" => registers.d = value as u8,
+            "e" => registers.e = value as u8,
+            "h" => registers.h = value as u8,
+            "l" => registers.l = value as u8,
+            "af" => registers.set16(Register16::AF, value & 0xFFF0),
+            "bc" => registers.set16(Register16::BC, value),
+            "de" => registers.set16(Register16::DE, value),
+            "hl" => registers.set16(Register16::HL, value),
+            "sp" => registers.sp = value,
+            "pc" => cpu.pc = value,
+            _ => return Err(format!("Unknown register {}", name)),
+        }
+        Ok(())
+    }
+
+    // Writes go straight to memory, bypassing watchpoints. ROM can't be edited since
+    // writes to it are handled by the MBC.
+    pub fn set_memory(&mut self, console: &mut Console, address: u16, value: u8) -> Result<(), String> {
+        if address < 0x8000 {
+            return Err(format!("Can't write to ROM @ {:04X}", address));
+        }
+        console.cpu.memory.poke(address, value);
+        Ok(())
+    }
+
+    // Registers and instruction at PC, in the same format as the trace
+    pub fn state(&self, console: &mut Console) -> String {
+        let cpu = &mut console.cpu;
+        let opcode = cpu.memory.peek(cpu.pc);
+        let descr = if opcode == 0xCB {
+            Cpu::decode_cb(cpu.memory.peek(cpu.pc.wrapping_add(1))).2
+        } else {
+            Cpu::decode(opcode).2
+        };
+        cpu.trace(descr)
+    }
+
+    // Shadow call stack, innermost frame last
+    pub fn call_stack<'a>(&self, console: &'a Console) -> &'a [Frame] {
+        console.cpu.call_stack.frames()
+    }
+
+    // Breakpoint location, with its symbol if there's one. E.g: "00:0150 (Main)"
+    fn describe(console: &Console, breakpoint: Breakpoint) -> String {
+        let bank = breakpoint.bank.unwrap_or(0);
+        match console.cpu.symbols.describe(bank, breakpoint.address) {
+            Some(name) => format!("{} ({})", breakpoint, name),
+            None => breakpoint.to_string(),
+        }
+    }
+
+    // Hex dump of `length` bytes starting at `address`
+    pub fn dump(&self, console: &Console, address: u16, length: u16) -> String {
+        let mut lines = vec![];
+        for row in (0..length).step_by(16) {
+            let start = address.wrapping_add(row);
+            let bytes = (0..16.min(length - row))
+                .map(|n| format!("{:02X}", console.cpu.memory.peek(start.wrapping_add(n))))
+                .collect::<Vec<String>>();
+            lines.push(format!("{:04X}: {}", start, bytes.join(" ")));
+        }
+        lines.join("\n")
+    }
+
+    // Run a REPL command and return its output. Commands that resume the execution
+    // only change the state, the caller is expected to call `tick` until it stops.
+    pub fn command(&mut self, console: &mut Console, line: &str) -> Result<String, String> {
+        let args = line.split_whitespace().collect::<Vec<&str>>();
+        let arg = |n: usize| args.get(n).copied().ok_or_else(|| "Missing argument".to_string());
+
+        match args.first().copied().unwrap_or("") {
+            "" => Ok(String::new()),
+            "c" | "continue" => { self.resume(console, Action::Continue); Ok(String::new()) }
+            "s" | "step" => { self.resume(console, Action::Step); Ok(String::new()) }
+            "n" | "next" => { self.resume(console, Action::StepOver); Ok(String::new()) }
+            "out" | "finish" => { self.resume(console, Action::StepOut); Ok(String::new()) }
+            "f" | "frame" => { self.resume(console, Action::RunToFrame); Ok(String::new()) }
+            "b" | "break" => {
+                let breakpoint = Breakpoint::parse_with_symbols(arg(1)?, &console.cpu.symbols)
+                    .ok_or("Invalid address or unknown symbol")?;
+                self.breakpoints.push(breakpoint);
+                Ok(format!("Breakpoint #{} @ {}", self.breakpoints.len() - 1, Self::describe(console, breakpoint)))
+            }
+            "d" | "delete" => {
+                let n = arg(1)?.parse::<usize>().map_err(|e| e.to_string())?;
+                if n >= self.breakpoints.len() {
+                    return Err(format!("No breakpoint #{}", n));
+                }
+                self.breakpoints.remove(n);
+                Ok(String::new())
+            }
+            "w" | "watch" => {
+                let address = parse_hex(arg(1)?).ok_or("Invalid address")?;
+                let mode = args.get(2).copied().unwrap_or("rw");
+                if !mode.contains('r') && !mode.contains('w') {
+                    return Err(format!("Invalid mode {}, expected r, w or rw", mode));
+                }
+                let watchpoint = Watchpoint { address, read: mode.contains('r'), write: mode.contains('w') };
+                self.add_watchpoint(console, watchpoint);
+                Ok(format!("Watchpoint #{} @ {:04X}", console.cpu.memory.watchpoints.len() - 1, address))
+            }
+            "unwatch" => {
+                let n = arg(1)?.parse::<usize>().map_err(|e| e.to_string())?;
+                if n >= console.cpu.memory.watchpoints.len() {
+                    return Err(format!("No watchpoint #{}", n));
+                }
+                console.cpu.memory.watchpoints.remove(n);
+                Ok(String::new())
+            }
+            "l" | "list" => {
+                let mut lines = self.breakpoints.iter().enumerate()
+                    .map(|(n, &b)| format!("Breakpoint #{} @ {}", n, Self::describe(console, b)))
+                    .collect::<Vec<String>>();
+                for (n, w) in console.cpu.memory.watchpoints.iter().enumerate() {
+                    let mode = format!("{}{}", if w.read { "r" } else { "" }, if w.write { "w" } else { "" });
+                    lines.push(format!("Watchpoint #{} @ {:04X} ({})", n, w.address, mode));
+                }
+                Ok(lines.join("\n"))
+            }
+            "r" | "regs" => Ok(self.state(console)),
+            "bt" | "backtrace" => Ok(console.cpu.backtrace()),
+            "hist" | "history" => Ok(console.cpu.recent_instructions()),
+            "set" => {
+                let value = parse_hex(arg(2)?).ok_or("Invalid value")?;
+                self.set_register(console, arg(1)?, value)?;
+                Ok(self.state(console))
+            }
+            "x" => {
+                let address = parse_hex(arg(1)?).ok_or("Invalid address")?;
+                let length = args.get(2).map_or(Some(16), |s| parse_hex(s)).ok_or("Invalid length")?;
+                Ok(self.dump(console, address, length))
+            }
+            "poke" => {
+                let address = parse_hex(arg(1)?).ok_or("Invalid address")?;
+                let value = parse_hex(arg(2)?).ok_or("Invalid value")?;
+                if value > 0xFF {
+                    return Err(format!("Value out of range {:X}", value));
+                }
+                self.set_memory(console, address, value as u8)?;
+                Ok(String::new())
+            }
+            "h" | "help" => Ok(HELP.to_string()),
+            cmd => Err(format!("Unknown command {}. Type help for a list of commands.", cmd)),
+        }
+    }
+}
+
+static HELP: &str = "\
+c, continue          Resume execution
+s, step              Execute one instruction
+n, next              Step over calls
+out, finish          Run until the current function returns
+f, frame             Run until the next frame
+b, break [bank:]addr Add a breakpoint
+b, break label       Add a breakpoint on a symbol
+d, delete n          Delete breakpoint #n
+w, watch addr [r|w]  Add a watchpoint on reads and/or writes
+unwatch n            Delete watchpoint #n
+l, list              List breakpoints and watchpoints
+r, regs              Show registers
+bt, backtrace        Show the call stack
+hist, history        Show the last executed instructions
+set reg value        Set a register (a, f, b.., af, bc.., sp, pc)
+x addr [length]      Dump memory
+poke addr value      Write to memory
+h, help              Show this help";
+
+// Parse a hex number, with an optional $ or 0x prefix
+fn parse_hex(s: &str) -> Option<u16> {
+    let s = s.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(s, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::make_console;
+
+    #[test]
+    fn test_breakpoint() {
+        let mut console = make_console(&[0x00, 0x00, 0x00, 0x00]);
+        let mut debugger = Debugger::new();
+        debugger.breakpoints.push(Breakpoint::parse("0102").unwrap());
+        debugger.breakpoints.push(Breakpoint::parse("01:0103").unwrap());
+
+        let stop = debugger.run(&mut console, Action::Continue, 100);
+        assert_eq!(stop, Some(Stop::Breakpoint(Breakpoint::new(0x102))));
+        assert_eq!(console.cpu.pc, 0x102);
+
+        // Bank 0 is always mapped at 0x0103
+        let stop = debugger.run(&mut console, Action::Continue, 100);
+        assert_eq!(stop, None);
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        // 0100: CALL 0110
+        // 0103: NOP
+        // 0110: NOP
+        // 0111: RET
+        let mut code = vec![0; 0x12];
+        code[0..3].copy_from_slice(&[0xCD, 0x10, 0x01]);
+        code[0x11] = 0xC9;
+        let mut console = make_console(&code);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.run(&mut console, Action::StepOver, 100), Some(Stop::Step));
+        assert_eq!(console.cpu.pc, 0x103);
+
+        console.cpu.pc = 0x100;
+        assert_eq!(debugger.run(&mut console, Action::Step, 100), Some(Stop::Step));
+        assert_eq!(console.cpu.pc, 0x110);
+        assert_eq!(debugger.run(&mut console, Action::StepOut, 100), Some(Stop::Step));
+        assert_eq!(console.cpu.pc, 0x103);
+
+        // Without a frame to return from, the next RET still counts
+        console.cpu.pc = 0x110;
+        console.cpu.registers.sp -= 2;
+        let sp = console.cpu.registers.sp;
+        console.cpu.memory.poke(sp, 0x03);
+        console.cpu.memory.poke(sp + 1, 0x01);
+        assert_eq!(debugger.run(&mut console, Action::StepOut, 100), Some(Stop::Step));
+        assert_eq!(console.cpu.pc, 0x103);
+    }
+
+    #[test]
+    fn test_step_out_with_pushes() {
+        // 0100: CALL 0110
+        // 0103: NOP
+        // 0110: PUSH BC
+        // 0111: POP BC
+        // 0112: RET
+        let mut code = vec![0; 0x13];
+        code[0..3].copy_from_slice(&[0xCD, 0x10, 0x01]);
+        code[0x10..0x13].copy_from_slice(&[0xC5, 0xC1, 0xC9]);
+        let mut console = make_console(&code);
+        let mut debugger = Debugger::new();
+
+        debugger.run(&mut console, Action::Step, 100);
+        debugger.run(&mut console, Action::Step, 100);
+        assert_eq!(console.cpu.pc, 0x111);
+        // The POP brings SP above where it was, but the function hasn't returned yet
+        assert_eq!(debugger.run(&mut console, Action::StepOut, 100), Some(Stop::Step));
+        assert_eq!(console.cpu.pc, 0x103);
+    }
+
+    #[test]
+    fn test_breakpoint_label() {
+        let mut console = make_console(&[0x00, 0x00, 0x00, 0x00]);
+        console.cpu.symbols = Symbols::parse("00:0100 Main\n00:0102 Main.loop\n01:4000 Banked\n");
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.command(&mut console, "break Main.loop"), Ok("Breakpoint #0 @ 0102 (Main.loop)".to_string()));
+        assert_eq!(debugger.command(&mut console, "b Banked"), Ok("Breakpoint #1 @ 01:4000 (Banked)".to_string()));
+        assert!(debugger.command(&mut console, "b Nowhere").is_err());
+
+        let stop = debugger.run(&mut console, Action::Continue, 100);
+        assert_eq!(stop, Some(Stop::Breakpoint(Breakpoint::new(0x102))));
+    }
+
+    #[test]
+    fn test_backtrace() {
+        // 0100: CALL 0110
+        // 0110: RST 38
+        let mut code = vec![0; 0x11];
+        code[0..3].copy_from_slice(&[0xCD, 0x10, 0x01]);
+        code[0x10] = 0xFF;
+        let mut console = make_console(&code);
+        console.cpu.symbols = Symbols::parse("00:0110 Func\n");
+        let mut debugger = Debugger::new();
+        debugger.command(&mut console, "b 38").unwrap();
+        debugger.run(&mut console, Action::Continue, 100);
+
+        assert_eq!(debugger.call_stack(&console).len(), 2);
+        assert_eq!(
+            debugger.command(&mut console, "bt"),
+            Ok("#0 0038\n#1 0038 (RST from 0110 <Func>)\n#2 0110 <Func> (called from 0100)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        // LD A, 0x42; LD (C000), A; NOP
+        let mut console = make_console(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x00]);
+        let mut debugger = Debugger::new();
+        debugger.command(&mut console, "watch C000 w").unwrap();
+
+        let stop = debugger.run(&mut console, Action::Continue, 100);
+        assert_eq!(stop, Some(Stop::Watchpoint(WatchHit { address: 0xC000, value: 0x42, write: true })));
+        assert_eq!(console.cpu.pc, 0x105);
+
+        assert!(debugger.command(&mut console, "watch C000 x").is_err());
+        assert_eq!(console.cpu.memory.watchpoints.len(), 1);
+
+        // Reading memory from the debugger doesn't trigger it
+        debugger.command(&mut console, "watch C000 r").unwrap();
+        debugger.command(&mut console, "x C000 1").unwrap();
+        assert_eq!(console.cpu.memory.watch_hit.get(), None);
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut console = make_console(&[]);
+        let mut debugger = Debugger::new();
+        debugger.command(&mut console, "set hl 1234").unwrap();
+        debugger.command(&mut console, "set a $42").unwrap();
+        debugger.command(&mut console, "poke C000 12").unwrap();
+        assert_eq!(console.cpu.registers.get16(Register16::HL), 0x1234);
+        assert_eq!(console.cpu.registers.a, 0x42);
+        assert_eq!(debugger.dump(&console, 0xC000, 2), "C000: 12 00");
+        assert!(debugger.command(&mut console, "set x 1").is_err());
+        assert!(debugger.command(&mut console, "poke 0100 12").is_err());
+        assert!(debugger.command(&mut console, "poke C000 123").is_err());
+        assert_eq!(debugger.dump(&console, 0xC000, 1), "C000: 12");
+    }
+}
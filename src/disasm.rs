@@ -0,0 +1,479 @@
+// SM83 disassembler. Instructions are decoded from the opcode bits rather than from the
+// OPCODES table so that operands can be resolved and the output follows the RGBDS syntax.
+// https://gbdev.io/gb-opcodes/optables/
+
+use crate::symbols::Symbols;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Write;
+use std::ops::Range;
+
+pub const BANK_SIZE: usize = 0x4000;
+
+// Where the execution starts in bank 0: the interrupt vectors and the cartridge entry point
+const ENTRY_POINTS: [u16; 6] = [0x40, 0x48, 0x50, 0x58, 0x60, 0x100];
+
+// Runs of identical data bytes at least this long are written as `ds`
+const MIN_FILL_LENGTH: usize = 16;
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const R16_MEMORY: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const SHIFTS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    None,
+    Byte(u8),
+    Word(u16),
+    Offset(i8),
+}
+
+// Where the execution goes after an instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    Next,
+    Jump(u16),
+    // Conditional jump: either the target or the next instruction
+    Branch(u16),
+    // The callee is expected to return to the next instruction
+    Call(u16),
+    // RET, RETI or JP HL: the execution doesn't continue with the next instruction
+    Return,
+    Invalid,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decoded {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub operand: Operand,
+    pub flow: Flow,
+    pub text: String,
+}
+
+impl Decoded {
+    pub fn length(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn target(&self) -> Option<u16> {
+        match self.flow {
+            Flow::Jump(target) | Flow::Branch(target) | Flow::Call(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    // Text with the branch target replaced by a label
+    pub fn text_with_label(&self, label: &str) -> String {
+        match self.target() {
+            Some(target) => self.text.replace(&format!("${:04X}", target), label),
+            None => self.text.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+        write!(f, "{:04X}: {:<8}  {}", self.address, bytes, self.text)
+    }
+}
+
+// Decode the instruction at `address`, reading memory through `read`
+pub fn decode(read: &dyn Fn(u16) -> u8, address: u16) -> Decoded {
+    let opcode = read(address);
+    let byte = read(address.wrapping_add(1));
+    let word = byte as u16 | (read(address.wrapping_add(2)) as u16) << 8;
+    let relative = address.wrapping_add(2).wrapping_add(byte as i8 as u16);
+
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 7) as usize;
+    let z = (opcode & 7) as usize;
+    let p = y >> 1;
+
+    let simple = |text: &str| (text.to_string(), Operand::None, Flow::Next);
+
+    let (text, operand, flow) = match opcode {
+        0x00 => simple("nop"),
+        // The byte after STOP is skipped. RGBDS writes it as 0 when it's not given.
+        0x10 if byte == 0 => ("stop".to_string(), Operand::Byte(byte), Flow::Next),
+        0x10 => (format!("stop ${:02X}", byte), Operand::Byte(byte), Flow::Next),
+        0x76 => simple("halt"),
+        0x07 => simple("rlca"),
+        0x0F => simple("rrca"),
+        0x17 => simple("rla"),
+        0x1F => simple("rra"),
+        0x27 => simple("daa"),
+        0x2F => simple("cpl"),
+        0x37 => simple("scf"),
+        0x3F => simple("ccf"),
+        0xF3 => simple("di"),
+        0xFB => simple("ei"),
+        0xF9 => simple("ld sp, hl"),
+        0xE2 => simple("ldh [c], a"),
+        0xF2 => simple("ldh a, [c]"),
+        0x08 => (format!("ld [${:04X}], sp", word), Operand::Word(word), Flow::Next),
+        0xEA => (format!("ld [${:04X}], a", word), Operand::Word(word), Flow::Next),
+        0xFA => (format!("ld a, [${:04X}]", word), Operand::Word(word), Flow::Next),
+        0xE0 => (format!("ldh [$FF{:02X}], a", byte), Operand::Byte(byte), Flow::Next),
+        0xF0 => (format!("ldh a, [$FF{:02X}]", byte), Operand::Byte(byte), Flow::Next),
+        0xE8 => (format!("add sp, {}", byte as i8), Operand::Offset(byte as i8), Flow::Next),
+        0xF8 => (format!("ld hl, sp{:+}", byte as i8), Operand::Offset(byte as i8), Flow::Next),
+        0x18 => (format!("jr ${:04X}", relative), Operand::Offset(byte as i8), Flow::Jump(relative)),
+        0x20 | 0x28 | 0x30 | 0x38 => (
+            format!("jr {}, ${:04X}", CONDITIONS[y - 4], relative),
+            Operand::Offset(byte as i8),
+            Flow::Branch(relative),
+        ),
+        0xC3 => (format!("jp ${:04X}", word), Operand::Word(word), Flow::Jump(word)),
+        0xC2 | 0xCA | 0xD2 | 0xDA => (
+            format!("jp {}, ${:04X}", CONDITIONS[y], word),
+            Operand::Word(word),
+            Flow::Branch(word),
+        ),
+        0xE9 => ("jp hl".to_string(), Operand::None, Flow::Return),
+        0xCD => (format!("call ${:04X}", word), Operand::Word(word), Flow::Call(word)),
+        0xC4 | 0xCC | 0xD4 | 0xDC => (
+            format!("call {}, ${:04X}", CONDITIONS[y], word),
+            Operand::Word(word),
+            Flow::Call(word),
+        ),
+        0xC9 => ("ret".to_string(), Operand::None, Flow::Return),
+        0xD9 => ("reti".to_string(), Operand::None, Flow::Return),
+        0xC0 | 0xC8 | 0xD0 | 0xD8 => simple(&format!("ret {}", CONDITIONS[y])),
+        0xCB => {
+            let (y, z) = (((byte >> 3) & 7) as usize, (byte & 7) as usize);
+            let text = match byte >> 6 {
+                0 => format!("{} {}", SHIFTS[y], R8[z]),
+                1 => format!("bit {}, {}", y, R8[z]),
+                2 => format!("res {}, {}", y, R8[z]),
+                _ => format!("set {}, {}", y, R8[z]),
+            };
+            (text, Operand::None, Flow::Next)
+        }
+        0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+            (format!("db ${:02X}", opcode), Operand::None, Flow::Invalid)
+        }
+        _ => match (x, z) {
+            (0, 1) if y & 1 == 0 => (format!("ld {}, ${:04X}", R16[p], word), Operand::Word(word), Flow::Next),
+            (0, 1) => simple(&format!("add hl, {}", R16[p])),
+            (0, 2) if y & 1 == 0 => simple(&format!("ld {}, a", R16_MEMORY[p])),
+            (0, 2) => simple(&format!("ld a, {}", R16_MEMORY[p])),
+            (0, 3) if y & 1 == 0 => simple(&format!("inc {}", R16[p])),
+            (0, 3) => simple(&format!("dec {}", R16[p])),
+            (0, 4) => simple(&format!("inc {}", R8[y])),
+            (0, 5) => simple(&format!("dec {}", R8[y])),
+            (0, 6) => (format!("ld {}, ${:02X}", R8[y], byte), Operand::Byte(byte), Flow::Next),
+            (1, _) => simple(&format!("ld {}, {}", R8[y], R8[z])),
+            (2, _) => simple(&format!("{} a, {}", ALU[y], R8[z])),
+            (3, 1) => simple(&format!("pop {}", R16_STACK[p])),
+            (3, 5) => simple(&format!("push {}", R16_STACK[p])),
+            (3, 6) => (format!("{} a, ${:02X}", ALU[y], byte), Operand::Byte(byte), Flow::Next),
+            (3, 7) => (format!("rst ${:02X}", y * 8), Operand::None, Flow::Call(y as u16 * 8)),
+            _ => unreachable!("opcode {:02X}", opcode),
+        },
+    };
+
+    // The CB prefix is followed by one more byte
+    let length = match operand {
+        Operand::None => 1,
+        Operand::Byte(_) | Operand::Offset(_) => 2,
+        Operand::Word(_) => 3,
+    } + if opcode == 0xCB { 1 } else { 0 };
+
+    Decoded {
+        address,
+        bytes: (0..length).map(|n| read(address.wrapping_add(n))).collect(),
+        operand,
+        flow,
+        text,
+    }
+}
+
+// Decode every instruction of a range, one after the other, without caring about data
+pub fn disassemble(read: &dyn Fn(u16) -> u8, range: Range<u16>) -> Vec<Decoded> {
+    let mut instructions = vec![];
+    let mut address = range.start as u32;
+    while address < range.end as u32 {
+        let decoded = decode(read, address as u16);
+        address += decoded.length() as u32;
+        instructions.push(decoded);
+    }
+    instructions
+}
+
+// Follow the control flow from the entry points and return the instructions reached,
+// by address. Targets outside of `range` are not followed. Anything else is data, or
+// code only reached through JP HL or jump tables.
+pub fn find_code(read: &dyn Fn(u16) -> u8, entry_points: &[u16], range: Range<u16>) -> BTreeMap<u16, Decoded> {
+    let mut code = BTreeMap::new();
+    let mut pending = entry_points.to_vec();
+
+    while let Some(address) = pending.pop() {
+        if !range.contains(&address) || code.contains_key(&address) {
+            continue;
+        }
+
+        let decoded = decode(read, address);
+        let next = address as u32 + decoded.length() as u32;
+        if decoded.flow == Flow::Invalid || next > range.end as u32 {
+            continue;
+        }
+
+        let next = next as u16;
+        match decoded.flow {
+            Flow::Next => pending.push(next),
+            Flow::Jump(target) => pending.push(target),
+            Flow::Branch(target) | Flow::Call(target) => {
+                pending.push(next);
+                pending.push(target);
+            }
+            Flow::Return | Flow::Invalid => {}
+        }
+        code.insert(address, decoded);
+    }
+
+    code
+}
+
+// Read ROM addresses as the CPU sees them with `bank` mapped at 0x4000-0x7FFF
+fn bank_reader(rom: &[u8], bank: usize) -> impl Fn(u16) -> u8 + '_ {
+    move |address| {
+        let offset = match address {
+            0x0000..=0x3FFF => address as usize,
+            _ => bank * BANK_SIZE + (address as usize & 0x3FFF),
+        };
+        rom.get(offset).copied().unwrap_or(0xFF)
+    }
+}
+
+// Find the code of a ROM bank. Switchable banks are entered from the targets of the
+// jumps and calls of bank 0 into 0x4000-0x7FFF.
+pub fn find_bank_code(rom: &[u8], bank: usize) -> BTreeMap<u16, Decoded> {
+    let bank0 = find_code(&bank_reader(rom, 1), &ENTRY_POINTS, 0x0000..0x4000);
+    if bank == 0 {
+        return bank0;
+    }
+
+    let entry_points = bank0.values()
+        .filter_map(|d| d.target())
+        .filter(|&target| target >= 0x4000)
+        .collect::<Vec<u16>>();
+    find_code(&bank_reader(rom, bank), &entry_points, 0x4000..0x8000)
+}
+
+// Disassemble a ROM bank to an RGBDS source. Symbols name the labels, other jump
+// targets get a generated label.
+pub fn disassemble_bank(rom: &[u8], bank: usize, symbols: &Symbols) -> String {
+    let read = bank_reader(rom, bank.max(1));
+    let code = find_bank_code(rom, bank);
+    let start: u16 = if bank == 0 { 0x0000 } else { 0x4000 };
+    let end = start as u32 + BANK_SIZE as u32;
+
+    // Instructions overlapping a previous one can't be written
+    let mut instructions = BTreeMap::new();
+    let mut address = start as u32;
+    while address < end {
+        match code.get(&(address as u16)) {
+            Some(decoded) => {
+                instructions.insert(address as u16, decoded);
+                address += decoded.length() as u32;
+            }
+            None => address += 1,
+        }
+    }
+
+    // Bank 0 is followed by bank 1 in ROMs without an MBC
+    let symbol = |address: u16| symbols.name(if address < 0x4000 { 0 } else { bank.max(1) }, address);
+    let mut labels = instructions.values()
+        .filter_map(|d| d.target())
+        .filter(|target| instructions.contains_key(target))
+        .collect::<BTreeSet<u16>>();
+    labels.extend(symbols.in_range(bank, start, (end - 1) as u16).map(|(address, _)| address));
+    let label = |address: u16| match symbol(address) {
+        Some(name) => name.to_string(),
+        None => format!("L{:02X}_{:04X}", bank, address),
+    };
+    // Symbols in the middle of an instruction can't be labels, they're written as comments
+    let hidden = instructions.iter()
+        .flat_map(|(&address, d)| address.wrapping_add(1)..address.wrapping_add(d.length()))
+        .filter(|address| labels.contains(address))
+        .collect::<BTreeSet<u16>>();
+
+    let mut out = String::new();
+    if bank == 0 {
+        writeln!(out, "SECTION \"ROM Bank $00\", ROM0[$0000]").unwrap();
+    } else {
+        writeln!(out, "SECTION \"ROM Bank ${:02X}\", ROMX[$4000], BANK[${:02X}]", bank, bank).unwrap();
+    }
+
+    let mut data = vec![];
+    let mut address = start as u32;
+    while address < end {
+        let current = address as u16;
+        let instruction = instructions.get(&current);
+
+        if (instruction.is_some() || labels.contains(&current)) && !data.is_empty() {
+            write_data(&mut out, current - data.len() as u16, &data);
+            data.clear();
+        }
+
+        if labels.contains(&current) {
+            writeln!(out, "\n{}:", label(current)).unwrap();
+        }
+
+        match instruction {
+            Some(decoded) => {
+                let text = match decoded.target() {
+                    Some(target) if hidden.contains(&target) => decoded.text.clone(),
+                    Some(target) if labels.contains(&target) => decoded.text_with_label(&label(target)),
+                    Some(target) if symbol(target).is_some() => decoded.text_with_label(&label(target)),
+                    _ => decoded.text.clone(),
+                };
+                writeln!(out, "    {:<32}; ${:04X}", text, current).unwrap();
+                for &inside in hidden.range(current + 1..current.wrapping_add(decoded.length())) {
+                    writeln!(out, "    ; {} = ${:04X}", label(inside), inside).unwrap();
+                }
+                address += decoded.length() as u32;
+            }
+            None => {
+                data.push(read(current));
+                address += 1;
+            }
+        }
+    }
+
+    if !data.is_empty() {
+        write_data(&mut out, (end - data.len() as u32) as u16, &data);
+    }
+
+    out
+}
+
+// Write data bytes 8 per line, and long runs of the same byte as a fill
+fn write_data(out: &mut String, address: u16, data: &[u8]) {
+    let mut n = 0;
+    while n < data.len() {
+        let run = data[n..].iter().take_while(|&&b| b == data[n]).count();
+        if run >= MIN_FILL_LENGTH {
+            let fill = format!("ds {}, ${:02X}", run, data[n]);
+            writeln!(out, "    {:<32}; ${:04X}", fill, address as usize + n).unwrap();
+            n += run;
+            continue;
+        }
+
+        // Stop before the next long run
+        let mut length = 1;
+        while length < 8 && n + length < data.len() {
+            let next = &data[n + length..];
+            if next.iter().take_while(|&&b| b == next[0]).count() >= MIN_FILL_LENGTH {
+                break;
+            }
+            length += 1;
+        }
+
+        let bytes = data[n..n + length].iter().map(|b| format!("${:02X}", b)).collect::<Vec<_>>().join(", ");
+        writeln!(out, "    {:<32}; ${:04X}", format!("db {}", bytes), address as usize + n).unwrap();
+        n += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(address: u16, bytes: &[u8]) -> Decoded {
+        let bytes = bytes.to_vec();
+        decode(&move |a| bytes.get(a.wrapping_sub(address) as usize).copied().unwrap_or(0), address)
+    }
+
+    // NOP; JP $0150, header, then at $0150:
+    // CALL $0160; loop: JR loop; at $0160: RET, followed by data
+    fn make_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x150..0x155].copy_from_slice(&[0xCD, 0x60, 0x01, 0x18, 0xFE]);
+        rom[0x160..0x163].copy_from_slice(&[0xC9, 0x12, 0x34]);
+        rom
+    }
+
+    #[test]
+    fn test_decode() {
+        let ld = decode_bytes(0x100, &[0xFA, 0x00, 0xC0]);
+        assert_eq!(ld.text, "ld a, [$C000]");
+        assert_eq!(ld.operand, Operand::Word(0xC000));
+        assert_eq!(ld.length(), 3);
+        assert_eq!(ld.flow, Flow::Next);
+
+        let jr = decode_bytes(0x100, &[0x20, 0xFE]);
+        assert_eq!(jr.text, "jr nz, $0100");
+        assert_eq!(jr.operand, Operand::Offset(-2));
+        assert_eq!(jr.flow, Flow::Branch(0x100));
+        assert_eq!(jr.text_with_label("Loop"), "jr nz, Loop");
+
+        assert_eq!(decode_bytes(0, &[0xE0, 0x44]).text, "ldh [$FF44], a");
+        assert_eq!(decode_bytes(0, &[0xCB, 0x7C]).text, "bit 7, h");
+        assert_eq!(decode_bytes(0, &[0xCB, 0x37]).text, "swap a");
+        assert_eq!(decode_bytes(0, &[0xF8, 0xFE]).text, "ld hl, sp-2");
+        assert_eq!(decode_bytes(0, &[0x2A]).text, "ld a, [hl+]");
+        assert_eq!(decode_bytes(0, &[0x96]).text, "sub a, [hl]");
+        assert_eq!(decode_bytes(0, &[0xF5]).text, "push af");
+        let stop = decode_bytes(0, &[0x10, 0x00]);
+        assert_eq!((stop.text.as_str(), stop.operand, stop.length()), ("stop", Operand::Byte(0), 2));
+        assert_eq!(decode_bytes(0, &[0x10, 0x42]).text, "stop $42");
+        assert_eq!(decode_bytes(0, &[0xFF]).flow, Flow::Call(0x38));
+        assert_eq!(decode_bytes(0, &[0xD9]).flow, Flow::Return);
+        assert_eq!(decode_bytes(0, &[0xD3]).flow, Flow::Invalid);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let rom = make_rom();
+        let read = bank_reader(&rom, 1);
+        let instructions = disassemble(&read, 0x150..0x155);
+        let addresses = instructions.iter().map(|d| d.address).collect::<Vec<u16>>();
+        assert_eq!(addresses, vec![0x150, 0x153]);
+    }
+
+    #[test]
+    fn test_find_code() {
+        let rom = make_rom();
+        let code = find_bank_code(&rom, 0);
+
+        for address in &[0x100, 0x101, 0x150, 0x153, 0x160] {
+            assert!(code.contains_key(address), "{:04X}", address);
+        }
+        assert!(!code.contains_key(&0x104));
+        assert!(!code.contains_key(&0x161));
+    }
+
+    #[test]
+    fn test_disassemble_bank() {
+        let rom = make_rom();
+        let source = disassemble_bank(&rom, 0, &Symbols::new());
+
+        assert!(source.starts_with("SECTION \"ROM Bank $00\", ROM0[$0000]\n"));
+        assert!(source.contains("\nL00_0150:\n    call L00_0160"));
+        assert!(source.contains("\nL00_0153:\n    jr L00_0153"));
+        assert!(source.contains("\nL00_0160:\n    ret"));
+        // The interrupt vectors are entry points, so $0040-$00FF are NOPs
+        assert!(source.contains("    ds 64, $00                      ; $0000\n"));
+        assert!(source.contains("    db $12, $34                     ; $0161\n"));
+        assert!(source.contains("    ds 16029, $00                   ; $0163\n"));
+    }
+
+    #[test]
+    fn test_disassemble_bank_symbols() {
+        let rom = make_rom();
+        let symbols = Symbols::parse("00:0150 Main\n00:0152 Main.high\n00:0153 Main.loop\n00:0160 Ret\n00:0161 Data\n");
+        let source = disassemble_bank(&rom, 0, &symbols);
+
+        assert!(source.contains("\nMain:\n    call Ret                        ; $0150\n    ; Main.high = $0152\n"));
+        assert!(source.contains("\nMain.loop:\n    jr Main.loop"));
+        assert!(source.contains("\nData:\n    db $12, $34"));
+    }
+}
@@ -0,0 +1,488 @@
+// GDB remote serial protocol stub, built on top of the debugger.
+// https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+//
+// Only the packets needed to read/write registers and memory, manage breakpoints and
+// watchpoints, continue and single-step are supported. The register layout is described
+// in sm83.xml, which is sent to the client as the target description.
+
+use crate::console::Console;
+use crate::debugger::{Action, Breakpoint, Debugger, Stop};
+use crate::memory::Watchpoint;
+use crate::register::Register16::*;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+static TARGET_XML: &str = include_str!("sm83.xml");
+
+// Number of steps between two checks for an interrupt request (Ctrl-C) from the client
+const POLL_INTERVAL: u64 = 4096;
+
+// What to do after handling a packet
+#[derive(Debug, PartialEq)]
+enum Response {
+    Reply(String),
+    Resume,
+    Detach(Option<String>),
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: Debugger,
+    buf: Vec<u8>,
+    ticks: u64,
+}
+
+impl GdbStub {
+    // Listen on a local port and wait for a client to connect
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, _) = listener.accept()?;
+        Ok(Self::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            debugger: Debugger::new(),
+            buf: vec![],
+            ticks: 0,
+        }
+    }
+
+    // Run one console step, or wait for commands from the client while paused.
+    // Returns false once the client is gone.
+    pub fn step(&mut self, console: &mut Console) -> io::Result<bool> {
+        while self.debugger.is_paused() {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(false),
+            };
+
+            match self.handle_packet(console, &packet) {
+                Response::Reply(reply) => self.send(&reply)?,
+                Response::Resume => {}
+                Response::Detach(reply) => {
+                    if let Some(reply) = reply {
+                        self.send(&reply)?;
+                    }
+                    return Ok(false);
+                }
+            }
+        }
+
+        if let Some(stop) = self.debugger.tick(console) {
+            self.send(&Self::stop_reply(stop))?;
+        }
+
+        self.ticks += 1;
+        if self.ticks % POLL_INTERVAL == 0 && self.interrupted()? {
+            self.debugger.pause();
+            self.send(&Self::stop_reply(Stop::Interrupted))?;
+        }
+
+        Ok(true)
+    }
+
+    fn handle_packet(&mut self, console: &mut Console, packet: &str) -> Response {
+        let cpu = &mut console.cpu;
+        let reply = |s: &str| Response::Reply(s.to_string());
+
+        match packet.chars().next() {
+            Some('?') => reply("S05"),
+            Some('g') => {
+                let regs = [AF, BC, DE, HL, SP].iter()
+                    .map(|&r| cpu.registers.get16(r))
+                    .chain(std::iter::once(cpu.pc))
+                    .map(encode_u16)
+                    .collect::<String>();
+                Response::Reply(regs)
+            }
+            Some('G') => {
+                let values = packet.as_bytes()[1..].chunks(4)
+                    .map(|c| std::str::from_utf8(c).ok().and_then(decode_u16))
+                    .collect::<Option<Vec<u16>>>();
+                match values {
+                    Some(values) if values.len() == 6 => {
+                        for (n, &value) in values.iter().enumerate() {
+                            Self::set_register(console, n, value);
+                        }
+                        reply("OK")
+                    }
+                    _ => reply("E01"),
+                }
+            }
+            Some('p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(n) if n < 6 => {
+                    let value = match n {
+                        0 => cpu.registers.get16(AF),
+                        1 => cpu.registers.get16(BC),
+                        2 => cpu.registers.get16(DE),
+                        3 => cpu.registers.get16(HL),
+                        4 => cpu.registers.sp,
+                        _ => cpu.pc,
+                    };
+                    Response::Reply(encode_u16(value))
+                }
+                _ => reply("E01"),
+            },
+            Some('P') => {
+                let mut parts = packet[1..].splitn(2, '=');
+                let n = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+                let value = parts.next().and_then(decode_u16);
+                match (n, value) {
+                    (Some(n), Some(value)) if n < 6 => {
+                        Self::set_register(console, n, value);
+                        reply("OK")
+                    }
+                    _ => reply("E01"),
+                }
+            }
+            Some('m') => match parse_range(&packet[1..]) {
+                Some((address, length)) => {
+                    let bytes = (0..length)
+                        .map(|n| format!("{:02x}", cpu.memory.peek(address.wrapping_add(n))))
+                        .collect::<String>();
+                    Response::Reply(bytes)
+                }
+                None => reply("E01"),
+            },
+            Some('M') => {
+                let mut parts = packet[1..].splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                let data = parts.next().map(decode_bytes);
+                match (range, data) {
+                    (Some((address, length)), Some(Some(data))) if data.len() == length as usize => {
+                        for (n, &byte) in data.iter().enumerate() {
+                            let address = address.wrapping_add(n as u16);
+                            if self.debugger.set_memory(console, address, byte).is_err() {
+                                return reply("E02");
+                            }
+                        }
+                        reply("OK")
+                    }
+                    _ => reply("E01"),
+                }
+            }
+            Some('Z') | Some('z') => self.handle_breakpoint(console, packet),
+            Some('c') | Some('s') => {
+                if let Ok(address) = u16::from_str_radix(&packet[1..], 16) {
+                    cpu.pc = address;
+                }
+                let action = if packet.starts_with('c') { Action::Continue } else { Action::Step };
+                self.debugger.resume(console, action);
+                Response::Resume
+            }
+            Some('H') => reply("OK"),
+            Some('D') => Response::Detach(Some("OK".to_string())),
+            Some('k') => Response::Detach(None),
+            Some('q') => Self::handle_query(packet),
+            _ => reply(""),
+        }
+    }
+
+    fn handle_query(packet: &str) -> Response {
+        if packet.starts_with("qSupported") {
+            return Response::Reply("PacketSize=4000;qXfer:features:read+".to_string());
+        }
+
+        if packet == "qAttached" {
+            return Response::Reply("1".to_string());
+        }
+
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let range = args.split_once(',').and_then(|(offset, length)| {
+                let offset = usize::from_str_radix(offset, 16).ok()?;
+                let length = usize::from_str_radix(length, 16).ok()?;
+                Some((offset, length))
+            });
+
+            return match range {
+                Some((offset, _)) if offset >= TARGET_XML.len() => Response::Reply("l".to_string()),
+                Some((offset, length)) => {
+                    let end = TARGET_XML.len().min(offset + length);
+                    let prefix = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    Response::Reply(format!("{}{}", prefix, &TARGET_XML[offset..end]))
+                }
+                None => Response::Reply("E01".to_string()),
+            };
+        }
+
+        Response::Reply(String::new())
+    }
+
+    // Z0/z0: software breakpoints, Z1/z1: hardware breakpoints (same thing for us),
+    // Z2/z2: write watchpoints, Z3/z3: read watchpoints, Z4/z4: access watchpoints
+    fn handle_breakpoint(&mut self, console: &mut Console, packet: &str) -> Response {
+        let insert = packet.starts_with('Z');
+        let mut parts = packet[1..].split(',');
+        let kind = parts.next();
+        let address = parts.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+        let length = parts.next().and_then(|l| u16::from_str_radix(l, 16).ok()).unwrap_or(1);
+
+        let address = match address {
+            Some(address) => address,
+            None => return Response::Reply("E01".to_string()),
+        };
+
+        match kind {
+            Some("0") | Some("1") => {
+                let breakpoint = Breakpoint::new(address);
+                if insert {
+                    self.debugger.breakpoints.push(breakpoint);
+                } else if let Some(n) = self.debugger.breakpoints.iter().position(|&b| b == breakpoint) {
+                    self.debugger.breakpoints.remove(n);
+                }
+            }
+            Some(kind @ "2") | Some(kind @ "3") | Some(kind @ "4") => {
+                for n in 0..length {
+                    let watchpoint = Watchpoint {
+                        address: address.wrapping_add(n),
+                        read: kind != "2",
+                        write: kind != "3",
+                    };
+                    let watchpoints = &mut console.cpu.memory.watchpoints;
+                    if insert {
+                        watchpoints.push(watchpoint);
+                    } else if let Some(n) = watchpoints.iter().position(|&w| w == watchpoint) {
+                        watchpoints.remove(n);
+                    }
+                }
+            }
+            _ => return Response::Reply(String::new()),
+        }
+
+        Response::Reply("OK".to_string())
+    }
+
+    fn set_register(console: &mut Console, n: usize, value: u16) {
+        let registers = &mut console.cpu.registers;
+        match n {
+            0 => registers.set16(AF, value & 0xFFF0),
+            1 => registers.set16(BC, value),
+            2 => registers.set16(DE, value),
+            3 => registers.set16(HL, value),
+            4 => registers.sp = value,
+            _ => console.cpu.pc = value,
+        }
+    }
+
+    fn stop_reply(stop: Stop) -> String {
+        match stop {
+            Stop::Watchpoint(hit) if hit.write => format!("T05watch:{:04x};", hit.address),
+            Stop::Watchpoint(hit) => format!("T05rwatch:{:04x};", hit.address),
+            Stop::Interrupted => "S02".to_string(),
+            _ => "S05".to_string(),
+        }
+    }
+
+    // Read the next packet, acknowledging it. Returns None when the connection is closed.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            if let Some(packet) = self.take_packet()? {
+                return Ok(Some(packet));
+            }
+
+            let mut chunk = [0; 1024];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    // Extract a complete "$data#checksum" packet from the buffer, if there's one
+    fn take_packet(&mut self) -> io::Result<Option<String>> {
+        let start = match self.buf.iter().position(|&b| b == b'$') {
+            Some(start) => start,
+            None => {
+                // Acks and interrupt requests received while paused don't matter
+                self.buf.clear();
+                return Ok(None);
+            }
+        };
+
+        let end = match self.buf[start..].iter().position(|&b| b == b'#') {
+            Some(end) if start + end + 2 < self.buf.len() => start + end,
+            _ => return Ok(None),
+        };
+
+        let data = self.buf[start + 1..end].to_vec();
+        let checksum = std::str::from_utf8(&self.buf[end + 1..end + 3]).ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+        self.buf.drain(..end + 3);
+
+        if checksum == Some(Self::checksum(&data)) {
+            self.stream.write_all(b"+")?;
+            Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+        } else {
+            self.stream.write_all(b"-")?;
+            Ok(None)
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, Self::checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+    }
+
+    // Check, without blocking, whether the client sent an interrupt request (0x03)
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut chunk = [0; 1024];
+        let result = match self.stream.read(&mut chunk) {
+            Ok(n) => {
+                self.buf.extend_from_slice(&chunk[..n]);
+                Ok(chunk[..n].contains(&0x03))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+}
+
+fn encode_u16(value: u16) -> String {
+    format!("{:02x}{:02x}", value & 0xFF, value >> 8)
+}
+
+fn decode_u16(s: &str) -> Option<u16> {
+    match decode_bytes(s)?.as_slice() {
+        &[lo, hi] => Some(lo as u16 | (hi as u16) << 8),
+        _ => None,
+    }
+}
+
+fn decode_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|n| u8::from_str_radix(s.get(n..n + 2)?, 16).ok())
+        .collect()
+}
+
+// Parse "addr,length"
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+    let (address, length) = s.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, u16::from_str_radix(length, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::make_console;
+
+    fn make_stub() -> (GdbStub, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (GdbStub::new(stream), client)
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, GdbStub::checksum(data.as_bytes()))
+    }
+
+    fn reply(s: &str) -> Response {
+        Response::Reply(s.to_string())
+    }
+
+    // Send a command that resumes the execution and run until it stops again
+    fn resume(stub: &mut GdbStub, client: &mut TcpStream, console: &mut Console, command: &str) {
+        client.write_all(packet(command).as_bytes()).unwrap();
+        assert!(stub.step(console).unwrap());
+        while !stub.debugger.is_paused() {
+            assert!(stub.step(console).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(packet("OK"), "$OK#9a");
+        assert_eq!(packet("S05"), "$S05#b8");
+    }
+
+    #[test]
+    fn test_registers() {
+        let (mut stub, _client) = make_stub();
+        let mut console = make_console(&[]);
+        console.cpu.registers.set16(AF, 0x12B0);
+        console.cpu.registers.set16(BC, 0x3456);
+        console.cpu.registers.set16(DE, 0x789A);
+        console.cpu.registers.set16(HL, 0xBCDE);
+        console.cpu.registers.sp = 0xFFFE;
+
+        assert_eq!(stub.handle_packet(&mut console, "g"), reply("b01256349a78debcfeff0001"));
+        assert_eq!(stub.handle_packet(&mut console, "P5=5001"), reply("OK"));
+        assert_eq!(console.cpu.pc, 0x150);
+        assert_eq!(stub.handle_packet(&mut console, "p5"), reply("5001"));
+        assert_eq!(stub.handle_packet(&mut console, "p6"), reply("E01"));
+        assert_eq!(stub.handle_packet(&mut console, "Gff0100020003000400050006"), reply("OK"));
+        assert_eq!(console.cpu.registers.get16(AF), 0x01F0);
+        assert_eq!(console.cpu.registers.get16(BC), 0x0200);
+        assert_eq!(console.cpu.registers.sp, 0x0500);
+        assert_eq!(console.cpu.pc, 0x0600);
+    }
+
+    #[test]
+    fn test_memory() {
+        let (mut stub, _client) = make_stub();
+        let mut console = make_console(&[0x12, 0x34]);
+
+        assert_eq!(stub.handle_packet(&mut console, "m100,2"), reply("1234"));
+        assert_eq!(stub.handle_packet(&mut console, "Mc000,2:abcd"), reply("OK"));
+        assert_eq!(stub.handle_packet(&mut console, "mc000,2"), reply("abcd"));
+        assert_eq!(stub.handle_packet(&mut console, "Mc000,2:ab"), reply("E01"));
+        assert_eq!(stub.handle_packet(&mut console, "M100,1:00"), reply("E02"));
+    }
+
+    #[test]
+    fn test_target_xml() {
+        let (mut stub, _client) = make_stub();
+        let mut console = make_console(&[]);
+
+        let packet = "qXfer:features:read:target.xml:0,10";
+        assert_eq!(stub.handle_packet(&mut console, packet), reply(&format!("m{}", &TARGET_XML[..0x10])));
+
+        let packet = format!("qXfer:features:read:target.xml:{:x},1000", TARGET_XML.len() - 10);
+        assert_eq!(stub.handle_packet(&mut console, &packet), reply("l</target>\n"));
+
+        let packet = format!("qXfer:features:read:target.xml:{:x},1000", TARGET_XML.len());
+        assert_eq!(stub.handle_packet(&mut console, &packet), reply("l"));
+    }
+
+    #[test]
+    fn test_session() {
+        let (mut stub, mut client) = make_stub();
+        // NOP; NOP; LD ($C000), A; NOP
+        let mut console = make_console(&[0x00, 0x00, 0xEA, 0x00, 0xC0, 0x00]);
+
+        client.write_all(packet("Z0,102,1").as_bytes()).unwrap();
+        resume(&mut stub, &mut client, &mut console, "s");
+        assert_eq!(console.cpu.pc, 0x101);
+
+        resume(&mut stub, &mut client, &mut console, "c");
+        assert_eq!(console.cpu.pc, 0x102);
+
+        client.write_all(packet("Z2,c000,1").as_bytes()).unwrap();
+        resume(&mut stub, &mut client, &mut console, "c");
+        assert_eq!(console.cpu.pc, 0x105);
+
+        client.write_all(packet("D").as_bytes()).unwrap();
+        assert!(!stub.step(&mut console).unwrap());
+        drop(stub);
+
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        let expected = ["OK", "S05", "S05", "OK", "T05watch:c000;", "OK"].iter()
+            .map(|r| format!("+{}", packet(r)))
+            .collect::<String>();
+        assert_eq!(received, expected);
+    }
+}
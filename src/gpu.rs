@@ -1,12 +1,75 @@
-// use crate::memory::Memory;
+use crate::palette::{ColorCorrection, Palettes, Rgb};
+use std::fmt;
 
-pub static COLOR_MAP: [(u8, u8, u8); 4] = [
-    (0x9B, 0xBC, 0x0F),
-    (0x8B, 0xAC, 0x0F),
-    (0x30, 0x62, 0x30),
-    (0x0F, 0x38, 0x0F),
+// Number of cycles in a full frame (154 lines of 456 cycles)
+const FRAME_CYCLES: u64 = 70224;
+
+// Layer that produced a pixel of the frame
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    Background = 0,
+    Window = 1,
+    Obj0 = 2, // Sprite using OBP0
+    Obj1 = 3, // Sprite using OBP1
+}
+
+impl Layer {
+    // Index of the output palette used by the layer
+    fn palette(self) -> usize {
+        match self {
+            Layer::Background | Layer::Window => 0,
+            Layer::Obj0 => 1,
+            Layer::Obj1 => 2,
+        }
+    }
+}
+
+// 3x5 glyphs of the hex digits used to annotate the debug views, one bit per pixel
+static DEBUG_FONT: [u16; 16] = [
+    0b111_101_101_101_111, // 0
+    0b010_110_010_010_111, // 1
+    0b111_001_111_100_111, // 2
+    0b111_001_111_001_111, // 3
+    0b101_101_111_001_001, // 4
+    0b111_100_111_001_111, // 5
+    0b111_100_111_101_111, // 6
+    0b111_001_001_001_001, // 7
+    0b111_101_111_101_111, // 8
+    0b111_101_111_001_111, // 9
+    0b111_101_111_101_101, // A
+    0b110_101_110_101_110, // B
+    0b111_100_100_100_111, // C
+    0b110_101_101_101_110, // D
+    0b111_100_111_100_111, // E
+    0b111_100_111_100_100, // F
 ];
 
+// Palette used to draw tiles in the debug views
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugPalette {
+    Bgp,
+    Obp0,
+    Obp1,
+    Identity, // Color numbers are used as shades directly
+}
+
+// Tile map drawn by the background debug view
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileMap {
+    Map9800,
+    Map9C00,
+}
+
+impl TileMap {
+    fn address(self) -> u16 {
+        match self {
+            TileMap::Map9800 => 0x9800,
+            TileMap::Map9C00 => 0x9C00,
+        }
+    }
+}
+
 #[derive(Debug)]
 enum Mode {
     VBlank,
@@ -24,6 +87,9 @@ pub struct Gpu {
     pub lyc: u8,
     pub lcd: u8,
     pub frame: Vec<u8>,
+    // Shade (0-3, after applying BGP/OBP0/OBP1) and layer of every pixel of the frame
+    pub indexed_frame: Vec<u8>,
+    pub layers: Vec<Layer>,
     pub frame_count: u64,
     pub vram: Vec<u8>,
     pub interrupts: u8,
@@ -38,6 +104,26 @@ pub struct Gpu {
     pub bg_palette: u8,
     pub obj_palette_0: u8,
     pub obj_palette_1: u8,
+    // OAM indices of the (up to 10) sprites selected for the current line, in drawing priority order
+    pub line_sprites: Vec<usize>,
+    palettes: Palettes,
+    color_correction: ColorCorrection,
+    // RGB colors of the BG, OBJ0 and OBJ1 shades, with color correction applied
+    output: [[Rgb; 4]; 3],
+    // Internal window line counter. Only advances on lines where the window was drawn.
+    pub window_line: u8,
+    // Set once LY has matched WY during the current frame
+    window_triggered: bool,
+    // The first frame after turning the LCD back on is not displayed
+    blank_frame: bool,
+    // Raw background/window color indices of the current line, used for BG-over-OBJ priority
+    bg_line: [u8; 160],
+}
+
+impl Default for Gpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Gpu {
@@ -53,41 +139,94 @@ impl Gpu {
             scroll_y: 0,
             window_x: 0,
             window_y: 0,
-            control: 0,
+            control: 0x91,
             interrupts: 0,
             bg_palette: 0,
             obj_palette_0: 0,
             obj_palette_1: 0,
             frame: vec![0; 160 * 144 * 4],
+            indexed_frame: vec![0; 160 * 144],
+            layers: vec![Layer::Background; 160 * 144],
             vram: vec![0; 0x2000],
             oam: vec![Sprite::new(); 0x40],
             voam: vec![0; 0x200],
             new_frame: false,
+            window_line: 0,
+            window_triggered: false,
+            blank_frame: false,
+            line_sprites: Vec::with_capacity(10),
+            palettes: Palettes::default(),
+            color_correction: ColorCorrection::None,
+            output: [Palettes::default().bg.0; 3],
+            bg_line: [0; 160],
+        }
+    }
+
+    pub fn palettes(&self) -> &Palettes {
+        &self.palettes
+    }
+
+    pub fn set_palettes(&mut self, palettes: Palettes) {
+        self.palettes = palettes;
+        self.update_output();
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.color_correction
+    }
+
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        self.color_correction = color_correction;
+        self.update_output();
+    }
+
+    fn update_output(&mut self) {
+        let palettes = [self.palettes.bg, self.palettes.obj0, self.palettes.obj1];
+        for (output, palette) in self.output.iter_mut().zip(palettes.iter()) {
+            for (color, &rgb) in output.iter_mut().zip(palette.0.iter()) {
+                *color = self.color_correction.apply(rgb);
+            }
         }
     }
 
+    // `new_frame` is left set until the console starts its next step, which spans several calls
     pub fn step(&mut self, cycles: u64) {
         self.cycles += cycles;
-        self.new_frame = false;
         self.interrupts = 0;
 
+        // The PPU is stopped while the LCD is off. We still signal new frames at the usual
+        // rate so that frontends keep presenting the (blank) screen and polling events.
+        if !self.lcd_on() {
+            if self.cycles >= FRAME_CYCLES {
+                self.cycles -= FRAME_CYCLES;
+                self.new_frame = true;
+            }
+            return;
+        }
+
         match self.mode {
             OamRead if self.cycles >= 80 => {
                 self.cycles -= 80;
+                if self.ly == self.window_y {
+                    self.window_triggered = true;
+                }
+                self.scan_oam();
                 self.set_mode(LcdTransfer)
             }
             LcdTransfer if self.cycles >= 172 => {
                 self.cycles -= 172;
-                if self.lcd_on() {
+                if !self.blank_frame {
                     if self.bg_priority() {
                         self.render_background();
                         if self.window_enabled() {
                             self.render_window();
                         }
                     } else {
-                        self.clear_frame();
+                        self.clear_line();
+                    }
+                    if self.sprites_enabled() {
+                        self.render_sprites();
                     }
-                    self.render_sprites();
                 }
                 self.set_mode(HBlank)
             }
@@ -108,16 +247,51 @@ impl Gpu {
                 self.ly += 1;
                 if self.ly >= 154 {
                     self.ly = 0;
+                    self.window_line = 0;
+                    self.window_triggered = false;
+                    self.blank_frame = false;
                     self.set_mode(OamRead);
                 }
             }
             _ => {}
         }
 
-        if self.ly == self.lyc {
-            self.lcd |= 0x40;
+        // STAT bit 2 follows LY == LYC, and the STAT interrupt fires when it gets set if bit 6
+        // enables it
+        let coincidence = self.ly == self.lyc;
+        if coincidence && self.lcd & 0x04 == 0 && self.lcd & 0x40 != 0 {
             self.interrupts |= 2;
         }
+        self.lcd = if coincidence { self.lcd | 0x04 } else { self.lcd & !0x04 };
+    }
+
+    // Writes to STAT (0xFF41). The mode and the coincidence flag are read-only.
+    pub fn set_status(&mut self, value: u8) {
+        self.lcd = self.lcd & 0x87 | value & 0x78;
+    }
+
+    // Writes to LCDC (0xFF40). Turning the LCD off resets LY and puts STAT in mode 0 until
+    // it gets turned back on, at which point the PPU restarts from the top of the screen.
+    pub fn set_control(&mut self, value: u8) {
+        let was_on = self.lcd_on();
+        self.control = value;
+
+        match (was_on, self.lcd_on()) {
+            (true, false) => {
+                self.ly = 0;
+                self.cycles = 0;
+                self.window_line = 0;
+                self.window_triggered = false;
+                self.set_mode(HBlank);
+                self.clear_frame();
+            }
+            (false, true) => {
+                self.cycles = 0;
+                self.blank_frame = true;
+                self.set_mode(OamRead);
+            }
+            _ => {}
+        }
     }
 
     fn render_background(&mut self) {
@@ -133,7 +307,6 @@ impl Gpu {
         let ly = self.ly as u16;
         let scroll_x = self.scroll_x as u16;
         let scroll_y = self.scroll_y as u16;
-        let tile_data = self.tile_data();
         let tile_map = self.tile_map();
         let y: u16 = ((scroll_y + ly) / 8) % 32;
         let y_offset = (scroll_y + ly) % 8;
@@ -141,18 +314,15 @@ impl Gpu {
         for px in 0..160 {
             let x = ((scroll_x + px) / 8) % 32;
             let tile = self.load(tile_map.wrapping_add(y * 32).wrapping_add(x));
-            let ptr = match tile_data {
-                0x9000 => (tile_data as i32 + (tile as i8 as i32 * 0x10)) as u16,
-                _      => tile_data.wrapping_add(tile as u16 * 0x10),
-            };
-            let ptr = ptr.wrapping_add(y_offset * 2);
+            let ptr = self.tile_address(tile).wrapping_add(y_offset * 2);
             let p0 = self.load(ptr);
             let p1 = self.load(ptr + 1);
             let colb = -(((px + scroll_x) as i32 % 8) - 7);
             let coln = if (p1 >> colb) & 1 == 1 { 1 } else { 0 };
             let coln = (coln << 1) | (if (p0 >> colb) & 1 == 1 { 1 } else { 0 });
             let color = colors[coln as usize];
-            self.set_pixel(px as u8, ly as u8, color);
+            self.bg_line[px as usize] = coln;
+            self.set_pixel(px as u8, ly as u8, color, Layer::Background);
         }
     }
 
@@ -165,102 +335,396 @@ impl Gpu {
             (palette >> 6) & 3,
         ];
 
+        // The window shows up once LY reached WY during the frame, as long as WX is on screen.
+        // WX < 7 shifts the window left, hiding its first columns.
+        if !self.window_triggered || self.window_x > 166 {
+            return;
+        }
+
         let ly = self.ly as u16;
-        let tile_data = self.tile_data();
         let tile_map = self.window_tile_map();
-        let window_y = ly - self.window_y as u16;
+        let window_y = self.window_line as u16;
         let y = window_y / 8;
         let y_offset = window_y % 8;
-        let window_x = self.window_x.wrapping_sub(7);
-
-        for px in 0..=159 {
-            if px < window_x {
-                continue;
-            }
+        let window_x = self.window_x as i16 - 7;
 
-            let x = (px - window_x) / 8;
-            let tile = self.load(tile_map.wrapping_add(y * 32).wrapping_add(x as u16));
-            let ptr = match tile_data {
-                0x9000 => (tile_data as i32 + (tile as i8 as i32 * 0x10)) as u16,
-                _      => tile_data.wrapping_add(tile as u16 * 0x10),
-            };
-            let ptr = ptr.wrapping_add(y_offset * 2);
+        for px in window_x.max(0)..160 {
+            let wx = (px - window_x) as u16;
+            let x = wx / 8;
+            let tile = self.load(tile_map.wrapping_add(y * 32).wrapping_add(x));
+            let ptr = self.tile_address(tile).wrapping_add(y_offset * 2);
             let p0 = self.load(ptr);
             let p1 = self.load(ptr + 1);
-            let colb = 7 - px % 8;
+            let colb = 7 - wx % 8;
             let pix0 = if p0 >> colb & 1 == 1 { 1 } else { 0 };
             let pix1 = if p1 >> colb & 1 == 1 { 2 } else { 0 };
             let coln = pix0 | pix1;
             let color = colors[coln as usize];
-            self.set_pixel(px as u8, ly as u8, color);
+            self.bg_line[px as usize] = coln;
+            self.set_pixel(px as u8, ly as u8, color, Layer::Window);
         }
+
+        self.window_line += 1;
     }
 
-    fn render_sprites(&mut self) {
-        let sprite_height = if self.control & 4 > 0 { 16 } else { 8 };
+    // Select the sprites to draw on the current line, like the hardware does during mode 2:
+    // OAM is scanned in order and only the first 10 sprites overlapping LY are kept, whatever
+    // their X coordinate. On DMG, the sprite with the smallest X is drawn on top and ties are
+    // broken by the OAM index.
+    // http://bgb.bircd.org/pandocs.htm#vramspriteattributetableoam
+    fn scan_oam(&mut self) {
+        let sprite_height = self.sprite_height();
         let ly = self.ly as i16;
 
-        let mut n = 0;
-        while n < 156 {
-            let sprite = self.oam[n / 4];
+        self.line_sprites.clear();
+        for (n, sprite) in self.oam.iter().take(40).enumerate() {
+            let y = sprite.y as i16 - 16;
+            if y <= ly && ly < y + sprite_height {
+                self.line_sprites.push(n);
+                if self.line_sprites.len() == 10 {
+                    break;
+                }
+            }
+        }
+
+        let oam = &self.oam;
+        self.line_sprites.sort_by_key(|&n| (oam[n].x, n));
+    }
+
+    fn render_sprites(&mut self) {
+        let sprite_height = self.sprite_height();
+        let ly = self.ly as i16;
+        // Pixels already taken by a higher priority sprite. An opaque pixel hides the sprites
+        // below it even when it is itself hidden behind the background.
+        let mut taken = [false; 160];
 
+        for n in 0..self.line_sprites.len() {
+            let sprite = self.oam[self.line_sprites[n]];
             let y = sprite.y as i16 - 16;
             let x = sprite.x as i16 - 8;
-            let on_scanline = (y <= ly) && (y + sprite_height) > ly;
 
-            if on_scanline {
-                let y_offset = if sprite.y_flip() {
-                    (sprite_height - 1) - (ly - y)
-                } else {
-                    ly - y
-                };
-                let ptr = ((sprite.index as i32 * 16) + ((y_offset as i32) * 2)) as u16;
-                let lo = self.load(0x8000 + ptr);
-                let hi = self.load(0x8000 + ptr + 1);
-
-                for idx_x in 0..=7 {
-                    let pixel_x = x + idx_x;
-                    if pixel_x >= 0 && pixel_x <= 160 {
-                        let mut bit = idx_x;
-                        if !sprite.x_flip() { bit = 7 - bit };
-                        let p0 = if (hi >> bit) & 1 == 1 { 2 } else { 0 };
-                        let p1 = if (lo >> bit) & 1 == 1 { 1 } else { 0 };
-                        let pixel = p0 | p1;
-                        let palette = if sprite.attrs & 0x8 == 0 { self.obj_palette_0 } else { self.obj_palette_1 };
-                        let color = self.sprite_pixel_color(palette, pixel);
-                        if pixel != 0 || sprite.attrs & 0x80 == 0 && self.is_pixel_blank(pixel_x as u8, ly as u8) {
-                            self.set_pixel(pixel_x as u8, ly as u8, color)
-                        }
-                    }
+            let y_offset = if sprite.y_flip() {
+                (sprite_height - 1) - (ly - y)
+            } else {
+                ly - y
+            };
+            // In 8x16 mode the tile index ignores bit 0
+            let index = if sprite_height == 16 { sprite.index & 0xFE } else { sprite.index };
+            let ptr = index as u16 * 16 + y_offset as u16 * 2;
+            let lo = self.load(0x8000 + ptr);
+            let hi = self.load(0x8000 + ptr + 1);
+            let (palette, layer) = if sprite.attrs & 0x10 == 0 {
+                (self.obj_palette_0, Layer::Obj0)
+            } else {
+                (self.obj_palette_1, Layer::Obj1)
+            };
+
+            for idx_x in 0..=7 {
+                let pixel_x = x + idx_x;
+                if !(0..160).contains(&pixel_x) || taken[pixel_x as usize] {
+                    continue;
+                }
+
+                let bit = if sprite.x_flip() { idx_x } else { 7 - idx_x };
+                let p0 = if (hi >> bit) & 1 == 1 { 2 } else { 0 };
+                let p1 = if (lo >> bit) & 1 == 1 { 1 } else { 0 };
+                let pixel = p0 | p1;
+                if pixel == 0 {
+                    continue;
+                }
+
+                taken[pixel_x as usize] = true;
+                if sprite.behind_bg() && self.bg_line[pixel_x as usize] != 0 {
+                    continue;
                 }
+
+                let color = self.sprite_pixel_color(palette, pixel);
+                self.set_pixel(pixel_x as u8, ly as u8, color, layer)
             }
-            n += 4
         }
     }
 
+    // Size of the image returned by render_debug_sprites: 8x5 cells of 24x32 pixels
+    pub fn debug_sprites_size(&self) -> (usize, usize) {
+        (8 * 24, 5 * 32)
+    }
+
+    // Render the 40 sprites of OAM in an RGBA image, in cells annotated with the sprite index
+    // and its X/Y coordinates (in hex, as stored in OAM). Flips and 8x16 mode are applied.
+    // The cell background tells whether the sprite is displayed:
+    // - gray: on screen
+    // - red: off screen
+    // - blue: hidden by the 10 sprites per line limit on every line it covers
     pub fn render_debug_sprites(&self) -> Vec<u8> {
-        let mut buf = vec![0; 160 * 144 * 3];
-
-        for y_sprite in 0..=4 {
-            for x_sprite in 0..=7 {
-                let sprite = self.oam[y_sprite * 8 + x_sprite];
-
-                for y in 0..=7 {
-                    let ptr = ((sprite.index as i32 * 16) + ((y as i32) * 2)) as u16;
-                    let lo = self.load(0x8000 + ptr);
-                    let hi = self.load(0x8000 + ptr + 1);
-                    for x in 0..=7 {
-                        let bit = 7 - x;
-                        let p0 = if (hi >> bit) & 1 == 1 { 2 } else { 0 };
-                        let p1 = if (lo >> bit) & 1 == 1 { 1 } else { 0 };
-                        let pixel = p0 | p1;
-                        let palette = if sprite.attrs & 0x8 == 0 { self.obj_palette_0 } else { self.obj_palette_1 };
+        let (width, height) = self.debug_sprites_size();
+        let mut buf = vec![0; width * height * 4];
+        let sprite_height = self.sprite_height() as usize;
+        let dropped = self.dropped_sprites();
+
+        for (n, sprite) in self.oam.iter().take(40).enumerate() {
+            let cell_x = (n % 8) * 24;
+            let cell_y = (n / 8) * 32;
+
+            let offscreen = sprite.x == 0 || sprite.x >= 168
+                || sprite.y as usize + sprite_height <= 16 || sprite.y >= 160;
+            let background = if offscreen {
+                (0x60, 0x20, 0x20)
+            } else if dropped[n] {
+                (0x20, 0x20, 0x60)
+            } else {
+                (0x30, 0x30, 0x30)
+            };
+            for y in 0..31 {
+                for x in 0..23 {
+                    Self::plot(&mut buf, width, cell_x + x, cell_y + y, background);
+                }
+            }
+
+            let (palette, layer) = if sprite.attrs & 0x10 == 0 {
+                (self.obj_palette_0, Layer::Obj0)
+            } else {
+                (self.obj_palette_1, Layer::Obj1)
+            };
+            let index = if sprite_height == 16 { sprite.index & 0xFE } else { sprite.index } as usize;
+
+            for y in 0..sprite_height {
+                let row = if sprite.y_flip() { sprite_height - 1 - y } else { y };
+                let lo = self.vram[index * 16 + row * 2];
+                let hi = self.vram[index * 16 + row * 2 + 1];
+                for x in 0..8 {
+                    let bit = if sprite.x_flip() { x } else { 7 - x };
+                    let p0 = if (hi >> bit) & 1 == 1 { 2 } else { 0 };
+                    let p1 = if (lo >> bit) & 1 == 1 { 1 } else { 0 };
+                    let pixel = p0 | p1;
+                    if pixel != 0 {
                         let color = self.sprite_pixel_color(palette, pixel) as usize;
-                        let offset = y * 160 + x + (x_sprite * 8) + (y_sprite * 8 * 160);
-                        buf[offset * 4 + 0] = COLOR_MAP[color].0;
-                        buf[offset * 4 + 1] = COLOR_MAP[color].1;
-                        buf[offset * 4 + 2] = COLOR_MAP[color].2;
-                        buf[offset * 4 + 3] = 0xFF;
+                        let rgb = self.output[layer.palette()][color];
+                        Self::plot(&mut buf, width, cell_x + 8 + x, cell_y + 1 + y, rgb);
+                    }
+                }
+            }
+
+            let white = (0xFF, 0xFF, 0xFF);
+            Self::draw_debug_text(&mut buf, width, cell_x + 2, cell_y + 19, &format!("{:02}", n), white);
+            let coords = format!("{:02X} {:02X}", sprite.x, sprite.y);
+            Self::draw_debug_text(&mut buf, width, cell_x + 2, cell_y + 25, &coords, white);
+        }
+
+        buf
+    }
+
+    // Sprites that are within the screen vertically but never drawn because of the
+    // 10 sprites per line limit
+    fn dropped_sprites(&self) -> [bool; 40] {
+        let sprite_height = self.sprite_height();
+        let mut on_screen = [false; 40];
+        let mut selected = [false; 40];
+
+        for ly in 0..144 {
+            let mut count = 0;
+            for (n, sprite) in self.oam.iter().take(40).enumerate() {
+                let y = sprite.y as i16 - 16;
+                if y <= ly && ly < y + sprite_height {
+                    on_screen[n] = true;
+                    if count < 10 {
+                        selected[n] = true;
+                        count += 1;
+                    }
+                }
+            }
+        }
+
+        let mut dropped = [false; 40];
+        for n in 0..40 {
+            dropped[n] = on_screen[n] && !selected[n];
+        }
+        dropped
+    }
+
+    // Write text with a tiny 3x5 font. Only hex digits and spaces are supported.
+    fn draw_debug_text(buf: &mut [u8], width: usize, x: usize, y: usize, text: &str, rgb: Rgb) {
+        for (n, c) in text.chars().enumerate() {
+            let glyph = match c.to_digit(16) {
+                Some(digit) => DEBUG_FONT[digit as usize],
+                None => continue,
+            };
+            for row in 0..5 {
+                for col in 0..3 {
+                    if glyph & (1 << (14 - row * 3 - col)) != 0 {
+                        Self::plot(buf, width, x + n * 4 + col, y + row, rgb);
+                    }
+                }
+            }
+        }
+    }
+
+    fn plot(buf: &mut [u8], width: usize, x: usize, y: usize, rgb: Rgb) {
+        let offset = (y * width + x) * 4;
+        if x >= width || offset >= buf.len() {
+            return;
+        }
+        buf[offset] = rgb.0;
+        buf[offset + 1] = rgb.1;
+        buf[offset + 2] = rgb.2;
+        buf[offset + 3] = 0xFF;
+    }
+
+    // Size of the image returned by render_debug_tiles: 16x24 tiles per VRAM bank, side by side
+    pub fn debug_tiles_size(&self) -> (usize, usize) {
+        (16 * 8 * (self.vram.len() / 0x2000), 24 * 8)
+    }
+
+    // Render the 384 tiles of every VRAM bank in an RGBA image
+    pub fn render_debug_tiles(&self, palette: DebugPalette) -> Vec<u8> {
+        let (width, height) = self.debug_tiles_size();
+        let mut buf = vec![0; width * height * 4];
+        let colors = self.debug_colors(palette);
+
+        for bank in 0..self.vram.len() / 0x2000 {
+            for tile in 0..384 {
+                let x = bank * 128 + (tile % 16) * 8;
+                let y = (tile / 16) * 8;
+                self.draw_debug_tile(&mut buf, width, bank * 0x2000 + tile * 16, x, y, &colors);
+            }
+        }
+
+        buf
+    }
+
+    // Render the full 256x256 background map at 0x9800 or 0x9C00 in an RGBA image, using the
+    // current tile data mode. The visible area (SCX/SCY) is outlined in red and the part of it
+    // covered by the window, if enabled, in blue.
+    pub fn render_debug_background(&self, map: TileMap) -> Vec<u8> {
+        let map = map.address();
+        let mut buf = vec![0; 256 * 256 * 4];
+        let colors = self.debug_colors(DebugPalette::Bgp);
+
+        for n in 0..0x400 {
+            let tile = self.load(map + n);
+            let address = (self.tile_address(tile) & 0x1FFF) as usize;
+            let x = (n as usize % 32) * 8;
+            let y = (n as usize / 32) * 8;
+            self.draw_debug_tile(&mut buf, 256, address, x, y, &colors);
+        }
+
+        let scroll_x = self.scroll_x as usize;
+        let scroll_y = self.scroll_y as usize;
+        if self.window_enabled() && self.window_x <= 166 && self.window_y < 144 {
+            let window_x = (self.window_x as usize).saturating_sub(7);
+            let window_y = self.window_y as usize;
+            let rect = (scroll_x + window_x, scroll_y + window_y, 160 - window_x, 144 - window_y);
+            Self::draw_debug_rect(&mut buf, rect, (0x00, 0x00, 0xFF));
+        }
+        Self::draw_debug_rect(&mut buf, (scroll_x, scroll_y, 160, 144), (0xFF, 0x00, 0x00));
+
+        buf
+    }
+
+    // Outline a (x, y, width, height) rectangle in a 256x256 RGBA buffer, wrapping around the edges
+    fn draw_debug_rect(buf: &mut [u8], rect: (usize, usize, usize, usize), rgb: Rgb) {
+        let (x, y, width, height) = rect;
+        let mut plot = |px: usize, py: usize| Self::plot(buf, 256, px % 256, py % 256, rgb);
+
+        for px in x..x + width {
+            plot(px, y);
+            plot(px, y + height - 1);
+        }
+        for py in y..y + height {
+            plot(x, py);
+            plot(x + width - 1, py);
+        }
+    }
+
+    // Decoded content of the 40 OAM entries
+    pub fn oam_table(&self) -> Vec<OamEntry> {
+        self.oam.iter().take(40).enumerate().map(|(n, sprite)| {
+            OamEntry {
+                index: n,
+                x: sprite.x,
+                y: sprite.y,
+                tile: sprite.index,
+                attrs: sprite.attrs,
+                x_flip: sprite.x_flip(),
+                y_flip: sprite.y_flip(),
+                behind_bg: sprite.behind_bg(),
+                palette: if sprite.attrs & 0x10 == 0 { 0 } else { 1 },
+                on_line: self.line_sprites.contains(&n),
+            }
+        }).collect()
+    }
+
+    // Size of the image returned by render_debug_oam_table: 2 columns of 20 rows
+    pub fn debug_oam_table_size(&self) -> (usize, usize) {
+        (2 * 80, 20 * 7)
+    }
+
+    // Render the OAM table in an RGBA image, one row per sprite with its index, X, Y, tile,
+    // attributes (in hex, except for the index) and OBP palette, followed by boxes lit for the
+    // X flip, Y flip and BG over OBJ flags. Sprites selected for the current line are in green.
+    pub fn render_debug_oam_table(&self) -> Vec<u8> {
+        let (width, height) = self.debug_oam_table_size();
+        let mut buf = vec![0; width * height * 4];
+        let white = (0xFF, 0xFF, 0xFF);
+
+        for entry in self.oam_table() {
+            let row_x = (entry.index / 20) * 80;
+            let row_y = (entry.index % 20) * 7;
+            let background = if entry.on_line {
+                (0x20, 0x50, 0x20)
+            } else if entry.index % 2 == 0 {
+                (0x30, 0x30, 0x30)
+            } else {
+                (0x20, 0x20, 0x20)
+            };
+            for y in 0..7 {
+                for x in 0..80 {
+                    Self::plot(&mut buf, width, row_x + x, row_y + y, background);
+                }
+            }
+
+            let text = format!(
+                "{:02} {:02X} {:02X} {:02X} {:02X} {}",
+                entry.index, entry.x, entry.y, entry.tile, entry.attrs, entry.palette
+            );
+            Self::draw_debug_text(&mut buf, width, row_x + 2, row_y + 1, &text, white);
+
+            for (n, &flag) in [entry.x_flip, entry.y_flip, entry.behind_bg].iter().enumerate() {
+                let color = if flag { white } else { (0x50, 0x50, 0x50) };
+                for y in 0..5 {
+                    for x in 0..3 {
+                        Self::plot(&mut buf, width, row_x + 67 + n * 4 + x, row_y + 1 + y, color);
+                    }
+                }
+            }
+        }
+
+        buf
+    }
+
+    // BGP, OBP0 and OBP1 with the RGB colors of their 4 color numbers.
+    // TODO: Add the CGB palette RAM once CGB mode is supported.
+    pub fn debug_palettes(&self) -> Vec<(&'static str, u8, [Rgb; 4])> {
+        vec![
+            ("BGP", self.bg_palette, self.debug_colors(DebugPalette::Bgp)),
+            ("OBP0", self.obj_palette_0, self.debug_colors(DebugPalette::Obp0)),
+            ("OBP1", self.obj_palette_1, self.debug_colors(DebugPalette::Obp1)),
+        ]
+    }
+
+    // Render the palettes as rows of 16x16 swatches in an RGBA image of 64 pixels wide
+    pub fn render_debug_palettes(&self) -> Vec<u8> {
+        let palettes = self.debug_palettes();
+        let mut buf = vec![0; 64 * 16 * palettes.len() * 4];
+
+        for (row, (_, _, colors)) in palettes.iter().enumerate() {
+            for (col, rgb) in colors.iter().enumerate() {
+                for y in 0..16 {
+                    for x in 0..16 {
+                        let offset = ((row * 16 + y) * 64 + col * 16 + x) * 4;
+                        buf[offset] = rgb.0;
+                        buf[offset + 1] = rgb.1;
+                        buf[offset + 2] = rgb.2;
+                        buf[offset + 3] = 0xFF;
                     }
                 }
             }
@@ -269,6 +733,39 @@ impl Gpu {
         buf
     }
 
+    // RGB colors of the 4 color numbers of a tile
+    fn debug_colors(&self, palette: DebugPalette) -> [Rgb; 4] {
+        let (palette, layer) = match palette {
+            DebugPalette::Bgp => (self.bg_palette, Layer::Background),
+            DebugPalette::Obp0 => (self.obj_palette_0, Layer::Obj0),
+            DebugPalette::Obp1 => (self.obj_palette_1, Layer::Obj1),
+            DebugPalette::Identity => (0b1110_0100, Layer::Background),
+        };
+        let output = &self.output[layer.palette()];
+
+        [
+            output[(palette & 3) as usize],
+            output[((palette >> 2) & 3) as usize],
+            output[((palette >> 4) & 3) as usize],
+            output[((palette >> 6) & 3) as usize],
+        ]
+    }
+
+    // Draw the 8x8 tile at the given VRAM offset into an RGBA buffer of the given width.
+    // Pixels falling outside of the buffer are skipped.
+    fn draw_debug_tile(&self, buf: &mut [u8], width: usize, tile: usize, x: usize, y: usize, colors: &[Rgb; 4]) {
+        for row in 0..8 {
+            let lo = self.vram[tile + row * 2];
+            let hi = self.vram[tile + row * 2 + 1];
+            for col in 0..8 {
+                let bit = 7 - col;
+                let p0 = if (hi >> bit) & 1 == 1 { 2 } else { 0 };
+                let p1 = if (lo >> bit) & 1 == 1 { 1 } else { 0 };
+                Self::plot(buf, width, x + col, y + row, colors[p0 | p1]);
+            }
+        }
+    }
+
     fn sprite_pixel_color(&self, palette: u8, pixel: u8) -> u8 {
         match pixel {
             1 => (palette >> 2) & 3,
@@ -291,8 +788,18 @@ impl Gpu {
         self.mode = mode;
     }
 
+    // VRAM can't be accessed by the CPU while the PPU is transferring pixels (mode 3)
+    pub fn vram_accessible(&self) -> bool {
+        !matches!(self.mode, LcdTransfer)
+    }
+
+    // OAM can't be accessed by the CPU during OAM search and pixel transfer (modes 2 and 3)
+    pub fn oam_accessible(&self) -> bool {
+        matches!(self.mode, HBlank | VBlank)
+    }
+
     pub fn load(&self, address: u16) -> u8 {
-        if address < 0x8000 || address > 0x9FFF {
+        if !(0x8000..=0x9FFF).contains(&address) {
             panic!(); // TODO: Fix this
         }
 
@@ -300,7 +807,7 @@ impl Gpu {
     }
 
     pub fn store(&mut self, address: u16, value: u8) {
-        if address < 0x8000 || address > 0x9FFF {
+        if !(0x8000..=0x9FFF).contains(&address) {
             panic!(); // TODO: Fix this
         }
 
@@ -336,25 +843,32 @@ impl Gpu {
         // dbg!(sprite);
     }
 
-    fn set_pixel(&mut self, x: u8, y: u8, color: u8) {
+    fn set_pixel(&mut self, x: u8, y: u8, color: u8, layer: Layer) {
         let offset = y as usize * 160 + x as usize;
-        let color = color as usize;
-        self.frame[offset*4] = COLOR_MAP[color].0;
-        self.frame[offset*4+1] = COLOR_MAP[color].1;
-        self.frame[offset*4+2] = COLOR_MAP[color].2;
+        self.indexed_frame[offset] = color;
+        self.layers[offset] = layer;
+        let rgb = self.output[layer.palette()][color as usize];
+        self.frame[offset*4] = rgb.0;
+        self.frame[offset*4+1] = rgb.1;
+        self.frame[offset*4+2] = rgb.2;
         self.frame[offset*4+3] = 0xFF;
     }
 
-    fn is_pixel_blank(&mut self, x: u8, y: u8) -> bool {
-        let offset = y as usize * 160 + x as usize;
-        self.frame[offset*3] == 0
-            && self.frame[offset*3+1] == 0
-            && self.frame[offset*3+2] == 0
+    // Blank the whole screen (white), like the LCD does when turned off
+    fn clear_frame(&mut self) {
+        for y in 0..144 {
+            for x in 0..160 {
+                self.set_pixel(x, y, 0, Layer::Background);
+            }
+        }
     }
 
-    fn clear_frame(&mut self) {
-        for v in &mut self.frame {
-            *v = 0;
+    // When the BG/window display bit is off, both layers are blank (white)
+    fn clear_line(&mut self) {
+        let ly = self.ly;
+        for px in 0..160 {
+            self.bg_line[px as usize] = 0;
+            self.set_pixel(px, ly, 0, Layer::Background);
         }
     }
 
@@ -365,6 +879,15 @@ impl Gpu {
         }
     }
 
+    // Address of a background/window tile, depending on the tile data mode.
+    // In 0x8800 mode, tile numbers are signed and relative to 0x9000.
+    fn tile_address(&self, tile: u8) -> u16 {
+        match self.tile_data() {
+            0x9000 => (0x9000 + (tile as i8 as i32 * 0x10)) as u16,
+            tile_data => tile_data + tile as u16 * 0x10,
+        }
+    }
+
     fn tile_map(&self) -> u16 {
         match self.control & 0x08 > 0 {
             true => 0x9C00,
@@ -379,6 +902,14 @@ impl Gpu {
         }
     }
 
+    fn sprite_height(&self) -> i16 {
+        if self.control & 0x04 > 0 { 16 } else { 8 }
+    }
+
+    fn sprites_enabled(&self) -> bool {
+        self.control & 0x02 != 0
+    }
+
     fn window_enabled(&self) -> bool {
         self.control & 0x20 != 0
     }
@@ -392,7 +923,40 @@ impl Gpu {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OamEntry {
+    pub index: usize,
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub attrs: u8,
+    pub x_flip: bool,
+    pub y_flip: bool,
+    pub behind_bg: bool,
+    pub palette: u8, // OBP0 or OBP1
+    pub on_line: bool, // Selected by the OAM scan of the current line
+}
+
+impl fmt::Display for OamEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{:02} X:{:3} Y:{:3} T:{:02X} A:{:02X} [{}{}{}] OBP{}{}",
+            self.index,
+            self.x,
+            self.y,
+            self.tile,
+            self.attrs,
+            if self.x_flip { 'X' } else { '-' },
+            if self.y_flip { 'Y' } else { '-' },
+            if self.behind_bg { 'B' } else { '-' },
+            self.palette,
+            if self.on_line { " *" } else { "" },
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
 pub struct Sprite {
     pub x: u8,
     pub y: u8,
@@ -417,4 +981,323 @@ impl Sprite {
     pub fn y_flip(&self) -> bool {
         self.attrs & 0x40 != 0
     }
+
+    pub fn behind_bg(&self) -> bool {
+        self.attrs & 0x80 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::{DMG_GREEN, POCKET, Palettes};
+
+    fn sprite(y: u8, x: u8) -> Sprite {
+        Sprite { y, x, index: 0, attrs: 0 }
+    }
+
+    #[test]
+    fn test_scan_oam_limit() {
+        let mut gpu = Gpu::new();
+        for n in 0..12 {
+            gpu.oam[n] = sprite(16, 8 + n as u8);
+        }
+        gpu.scan_oam();
+        assert_eq!(gpu.line_sprites, (0..10).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_scan_oam_x_priority() {
+        let mut gpu = Gpu::new();
+        gpu.oam[0] = sprite(16, 40);
+        gpu.oam[1] = sprite(16, 20);
+        gpu.oam[2] = sprite(16, 40);
+        gpu.oam[3] = sprite(100, 0); // Not on the current line
+        gpu.scan_oam();
+        assert_eq!(gpu.line_sprites, vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn test_sprite_behind_bg() {
+        let mut gpu = Gpu::new();
+        gpu.control = 0x82;
+        gpu.obj_palette_0 = 0b1110_0100;
+        // Tile 0 is fully opaque with color 3
+        for n in 0..16 {
+            gpu.vram[n] = 0xFF;
+        }
+        gpu.oam[0] = Sprite { y: 16, x: 8, index: 0, attrs: 0x80 };
+        gpu.bg_line[0] = 1;
+        gpu.scan_oam();
+        gpu.render_sprites();
+        assert_eq!(&gpu.frame[0..3], &[0, 0, 0]);
+        assert_eq!(gpu.frame[4..7], [DMG_GREEN.0[3].0, DMG_GREEN.0[3].1, DMG_GREEN.0[3].2]);
+        assert_eq!(gpu.layers[0..2], [Layer::Background, Layer::Obj0]);
+        assert_eq!(gpu.indexed_frame[0..2], [0, 3]);
+    }
+
+    fn run_until_line(gpu: &mut Gpu, ly: u8) {
+        while gpu.ly != ly {
+            gpu.step(4);
+        }
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut gpu = Gpu::new();
+        gpu.control = 0xA1;
+        gpu.window_x = 7;
+        gpu.window_y = 5;
+
+        run_until_line(&mut gpu, 10);
+        assert_eq!(gpu.window_line, 5);
+
+        // Disabling the window mid-frame freezes the counter
+        gpu.control &= !0x20;
+        run_until_line(&mut gpu, 20);
+        assert_eq!(gpu.window_line, 5);
+
+        gpu.control |= 0x20;
+        run_until_line(&mut gpu, 22);
+        assert_eq!(gpu.window_line, 7);
+
+        // Off-screen windows don't advance it either
+        gpu.window_x = 167;
+        run_until_line(&mut gpu, 30);
+        assert_eq!(gpu.window_line, 7);
+
+        run_until_line(&mut gpu, 0);
+        assert_eq!(gpu.window_line, 0);
+    }
+
+    #[test]
+    fn test_window_edges() {
+        let mut gpu = Gpu::new();
+        // Window map at 0x9C00, tiles at 0x8000
+        gpu.control = 0xF1;
+        gpu.bg_palette = 0b1110_0100;
+        gpu.window_y = 10;
+        // The window only shows tile 1, which has color 1 in its 5th column. The background is color 0.
+        for row in 0..8 {
+            gpu.vram[0x10 + row * 2] = 0x08;
+        }
+        for n in 0x1C00..0x2000 {
+            gpu.vram[n] = 1;
+        }
+
+        // WX < 7 hides the first 7 - WX columns of the window
+        gpu.window_x = 3;
+        run_until_line(&mut gpu, 11);
+        let line = 10 * 160;
+        assert_eq!(gpu.layers[line], Layer::Window);
+        assert_eq!(gpu.indexed_frame[line..line + 5], [1, 0, 0, 0, 0]);
+        assert_eq!(gpu.indexed_frame[line + 8], 1);
+        assert_eq!(gpu.window_line, 1);
+
+        // WX = 166 only shows the first column of the window, and still counts as a window line
+        gpu.window_x = 166;
+        run_until_line(&mut gpu, 12);
+        let line = 11 * 160;
+        assert_eq!(gpu.layers[line + 158], Layer::Background);
+        assert_eq!(gpu.layers[line + 159], Layer::Window);
+        assert_eq!(gpu.indexed_frame[line + 159], 0);
+        assert_eq!(gpu.window_line, 2);
+    }
+
+    #[test]
+    fn test_lyc_interrupt() {
+        let mut gpu = Gpu::new();
+        gpu.lyc = 3;
+        run_until_line(&mut gpu, 3);
+        assert_eq!(gpu.lcd & 0x04, 0x04);
+        // Disabled in STAT
+        assert_eq!(gpu.interrupts, 0);
+
+        gpu.lyc = 5;
+        // The coincidence flag can't be written
+        gpu.set_status(0x40);
+        assert_eq!(gpu.lcd & 0x44, 0x44);
+        let mut raised = 0;
+        while gpu.ly != 6 {
+            gpu.step(4);
+            if gpu.interrupts & 2 != 0 {
+                assert_eq!(gpu.ly, 5);
+                raised += 1;
+            }
+        }
+        // Only on the rising edge, not on every step of the line
+        assert_eq!(raised, 1);
+        assert_eq!(gpu.lcd & 0x04, 0);
+    }
+
+    #[test]
+    fn test_lcd_off() {
+        let mut gpu = Gpu::new();
+        run_until_line(&mut gpu, 42);
+
+        gpu.set_control(0x11);
+        assert_eq!(gpu.ly, 0);
+        assert_eq!(gpu.lcd & 3, 0);
+
+        gpu.step(FRAME_CYCLES / 2);
+        assert_eq!(gpu.ly, 0);
+        assert_eq!(gpu.lcd & 3, 0);
+        assert!(!gpu.new_frame);
+        gpu.step(FRAME_CYCLES / 2);
+        assert!(gpu.new_frame);
+        assert_eq!(gpu.interrupts, 0);
+    }
+
+    #[test]
+    fn test_lcd_on_blank_frame() {
+        let mut gpu = Gpu::new();
+        gpu.set_control(0x11);
+        // Every tile is filled with color 3
+        for n in 0..0x1800 {
+            gpu.vram[n] = 0xFF;
+        }
+        gpu.bg_palette = 0b1110_0100;
+
+        gpu.set_control(0x91);
+        assert_eq!(gpu.lcd & 3, 2);
+        run_until_line(&mut gpu, 144);
+        assert_eq!(gpu.frame[0..3], [DMG_GREEN.0[0].0, DMG_GREEN.0[0].1, DMG_GREEN.0[0].2]);
+
+        run_until_line(&mut gpu, 1);
+        assert_eq!(gpu.frame[0..3], [DMG_GREEN.0[3].0, DMG_GREEN.0[3].1, DMG_GREEN.0[3].2]);
+    }
+
+    #[test]
+    fn test_output_palettes() {
+        let mut gpu = Gpu::new();
+        gpu.set_palettes(Palettes { bg: DMG_GREEN, obj0: DMG_GREEN, obj1: POCKET });
+        gpu.obj_palette_1 = 0b1110_0100;
+        for n in 0..16 {
+            gpu.vram[n] = 0xFF;
+        }
+        gpu.oam[0] = Sprite { y: 16, x: 8, index: 0, attrs: 0x10 };
+        gpu.scan_oam();
+        gpu.render_sprites();
+        assert_eq!(gpu.frame[0..3], [POCKET.0[3].0, POCKET.0[3].1, POCKET.0[3].2]);
+
+        gpu.set_color_correction(ColorCorrection::Cgb);
+        gpu.render_sprites();
+        let rgb = ColorCorrection::Cgb.apply(POCKET.0[3]);
+        assert_eq!(gpu.frame[0..3], [rgb.0, rgb.1, rgb.2]);
+    }
+
+    #[test]
+    fn test_render_debug_tiles() {
+        let mut gpu = Gpu::new();
+        // First row of tile 17: color 1, 2, 3 then 0 for the rest
+        gpu.vram[17 * 16] = 0b1010_0000;
+        gpu.vram[17 * 16 + 1] = 0b0110_0000;
+
+        assert_eq!(gpu.debug_tiles_size(), (128, 192));
+        let buf = gpu.render_debug_tiles(DebugPalette::Identity);
+        let offset = (8 * 128 + 8) * 4;
+        for (n, &color) in [1, 2, 3, 0].iter().enumerate() {
+            let rgb = DMG_GREEN.0[color];
+            assert_eq!(buf[offset + n * 4..offset + n * 4 + 4], [rgb.0, rgb.1, rgb.2, 0xFF]);
+        }
+
+        gpu.bg_palette = 0b0001_1011;
+        let buf = gpu.render_debug_tiles(DebugPalette::Bgp);
+        let rgb = DMG_GREEN.0[2];
+        assert_eq!(buf[offset..offset + 3], [rgb.0, rgb.1, rgb.2]);
+    }
+
+    #[test]
+    fn test_render_debug_background() {
+        let mut gpu = Gpu::new();
+        gpu.control = 0x81;
+        gpu.bg_palette = 0b1110_0100;
+        gpu.scroll_x = 200;
+        // Tile 0x80 is at 0x8800 in signed mode
+        gpu.vram[0x1800] = 0x80;
+        for n in 0..16 {
+            gpu.vram[0x800 + n] = 0xFF;
+        }
+
+        let buf = gpu.render_debug_background(TileMap::Map9800);
+        let rgb = DMG_GREEN.0[3];
+        assert_eq!(buf[(256 + 1) * 4..(256 + 1) * 4 + 3], [rgb.0, rgb.1, rgb.2]);
+        let rgb = DMG_GREEN.0[0];
+        assert_eq!(buf[(256 + 9) * 4..(256 + 9) * 4 + 3], [rgb.0, rgb.1, rgb.2]);
+        // The viewport wraps around the right edge
+        assert_eq!(buf[200 * 4..200 * 4 + 3], [0xFF, 0, 0]);
+        assert_eq!(buf[(256 + 103) * 4..(256 + 103) * 4 + 3], [0xFF, 0, 0]);
+    }
+
+    #[test]
+    fn test_oam_table() {
+        let mut gpu = Gpu::new();
+        gpu.oam[3] = Sprite { y: 16, x: 20, index: 0x42, attrs: 0xF0 };
+        gpu.scan_oam();
+
+        let table = gpu.oam_table();
+        assert_eq!(table.len(), 40);
+        assert_eq!(table[3], OamEntry {
+            index: 3,
+            x: 20,
+            y: 16,
+            tile: 0x42,
+            attrs: 0xF0,
+            x_flip: true,
+            y_flip: true,
+            behind_bg: true,
+            palette: 1,
+            on_line: true,
+        });
+        assert!(!table[4].on_line);
+        assert_eq!(table[3].to_string(), "#03 X: 20 Y: 16 T:42 A:F0 [XYB] OBP1 *");
+    }
+
+    #[test]
+    fn test_render_debug_oam_table() {
+        let mut gpu = Gpu::new();
+        gpu.oam[3] = Sprite { y: 16, x: 20, index: 0x42, attrs: 0x20 };
+        gpu.oam[23] = Sprite { y: 0, x: 0, index: 0, attrs: 0x40 };
+        gpu.scan_oam();
+
+        let (width, _) = gpu.debug_oam_table_size();
+        let buf = gpu.render_debug_oam_table();
+        let pixel = |x: usize, y: usize| buf[(y * width + x) * 4..(y * width + x) * 4 + 3].to_vec();
+
+        // Selected for the current line
+        assert_eq!(pixel(0, 3 * 7), vec![0x20, 0x50, 0x20]);
+        assert_eq!(pixel(0, 4 * 7), vec![0x30, 0x30, 0x30]);
+        // Top left corner of the "0" and "3" of the index
+        assert_eq!(pixel(2, 3 * 7 + 1), vec![0xFF, 0xFF, 0xFF]);
+        assert_eq!(pixel(6, 3 * 7 + 1), vec![0xFF, 0xFF, 0xFF]);
+        // X flip set, Y flip not
+        assert_eq!(pixel(67, 3 * 7 + 1), vec![0xFF, 0xFF, 0xFF]);
+        assert_eq!(pixel(71, 3 * 7 + 1), vec![0x50, 0x50, 0x50]);
+        // Sprite 23 is on the second column, with its Y flip set
+        assert_eq!(pixel(80 + 71, 3 * 7 + 1), vec![0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_render_debug_sprites() {
+        let mut gpu = Gpu::new();
+        gpu.control |= 0x04;
+        gpu.obj_palette_0 = 0b1110_0100;
+        // Tile 2 has its leftmost pixel set on the first row, tile 3 is empty
+        gpu.vram[2 * 16] = 0x80;
+        gpu.vram[2 * 16 + 1] = 0x80;
+        gpu.oam[0] = Sprite { y: 16, x: 8, index: 3, attrs: 0x60 };
+        gpu.oam[1] = Sprite { y: 0, x: 8, index: 2, attrs: 0 };
+
+        let (width, _) = gpu.debug_sprites_size();
+        let buf = gpu.render_debug_sprites();
+        let pixel = |x: usize, y: usize| buf[(y * width + x) * 4..(y * width + x) * 4 + 3].to_vec();
+        let rgb = DMG_GREEN.0[3];
+
+        // 8x16 ignores bit 0 of the index, and both flips move the pixel to the bottom right
+        assert_eq!(pixel(8 + 7, 1 + 15), vec![rgb.0, rgb.1, rgb.2]);
+        assert_eq!(pixel(8, 1), vec![0x30, 0x30, 0x30]);
+        // Off screen sprites get a red background
+        assert_eq!(pixel(24 + 8, 1), vec![rgb.0, rgb.1, rgb.2]);
+        assert_eq!(pixel(24 + 9, 1), vec![0x60, 0x20, 0x20]);
+    }
 }
@@ -28,18 +28,27 @@ pub struct Input {
     pub dpad: u8,
     pub buttons: u8,
     pub selector: u8,
+    // Set when a selected line goes from high to low, until the memory takes it
+    pub interrupt: bool,
 }
 
 static DPAD_FLAG: u8 = 0x20;
 static BUTTONS_FLAG: u8 = 0x10;
 static SELECTOR_DEFAULT: u8 = 0xC0;
 
+impl Default for Input {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Input {
     pub fn new() -> Self {
         Self {
             dpad: 0x0F,
             buttons: 0x0F,
             selector: SELECTOR_DEFAULT,
+            interrupt: false,
         }
     }
 
@@ -54,16 +63,26 @@ impl Input {
     }
 
     pub fn set(&mut self, value: u8) {
+        let lines = self.get();
         self.selector = value | SELECTOR_DEFAULT;
+        self.check_interrupt(lines);
     }
 
     pub fn key_down(&mut self, button: Button) {
+        let lines = self.get();
         match button {
             A | B | Select | Start => {
                 self.buttons &= !button.to_bitflag();
             }
             _ => self.dpad &= !button.to_bitflag()
         }
+        self.check_interrupt(lines);
+    }
+
+    fn check_interrupt(&mut self, previous: u8) {
+        if previous & !self.get() & 0x0F != 0 {
+            self.interrupt = true;
+        }
     }
 
     pub fn key_up(&mut self, button: Button) {
@@ -89,4 +108,19 @@ mod tests {
         input.set(0b0001_0000);
         assert_eq!(input.get(), BUTTONS_FLAG | 0b1101_1011);
     }
+
+    #[test]
+    fn test_interrupt() {
+        let mut input = Input::new();
+        input.set(DPAD_FLAG);
+        input.key_down(Button::A);
+        assert!(!input.interrupt);
+        input.key_down(Button::Up);
+        assert!(input.interrupt);
+
+        // Selecting the buttons with A held down is a transition too
+        input.interrupt = false;
+        input.set(BUTTONS_FLAG);
+        assert!(input.interrupt);
+    }
 }
\ No newline at end of file
@@ -1,9 +1,22 @@
+pub mod backtrace;
 pub mod cartridge;
 pub mod console;
+pub mod coverage;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
 pub mod gpu;
 pub mod input;
+pub mod link;
 pub mod opcodes;
 pub mod memory;
+pub mod palette;
+pub mod png;
+pub mod printer;
+pub mod profiler;
 pub mod register;
+pub mod serial;
+pub mod symbols;
 pub mod timer;
+pub mod trace;
@@ -0,0 +1,427 @@
+// Link cables: between two emulator instances over TCP, or between two consoles in the same
+// process.
+//
+// Both sides count their M-cycles and send the count to each other, and neither runs more
+// than WINDOW M-cycles ahead of the last count it got. A transfer started by the side driving
+// the clock at cycle C reaches the other side at its own cycle C + WINDOW, which it can't have
+// passed yet. Both sides then see every byte at the same point of their execution, whatever
+// the network latency. The side driving the clock waits for the other side's byte in return.
+
+use crate::console::Console;
+use crate::serial::SerialDevice;
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+
+// M-cycles, the length of a transfer at 8192 Hz
+const WINDOW: u64 = 1024;
+// M-cycles between two cycle counts sent to the other side
+const SYNC_INTERVAL: u64 = 256;
+
+// Tag, cycle and byte
+const MESSAGE_SIZE: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Message {
+    Sync(u64),
+    Transfer(u64, u8),
+    Reply(u8),
+}
+
+impl Message {
+    fn encode(self) -> [u8; MESSAGE_SIZE] {
+        let (tag, cycle, byte) = match self {
+            Message::Sync(cycle) => (b'S', cycle, 0),
+            Message::Transfer(cycle, byte) => (b'T', cycle, byte),
+            Message::Reply(byte) => (b'R', 0, byte),
+        };
+
+        let mut bytes = [0; MESSAGE_SIZE];
+        bytes[0] = tag;
+        bytes[1..9].copy_from_slice(&cycle.to_le_bytes());
+        bytes[9] = byte;
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut cycle = [0; 8];
+        cycle.copy_from_slice(&bytes[1..9]);
+        let cycle = u64::from_le_bytes(cycle);
+
+        match bytes[0] {
+            b'S' => Some(Message::Sync(cycle)),
+            b'T' => Some(Message::Transfer(cycle, bytes[9])),
+            b'R' => Some(Message::Reply(bytes[9])),
+            _ => None,
+        }
+    }
+}
+
+pub struct LinkCable {
+    // None once the other side is gone, the cable then behaves as if unplugged
+    stream: Option<TcpStream>,
+    buf: Vec<u8>,
+    cycles: u64,
+    peer_cycles: u64,
+    last_sync: u64,
+    // Byte sent by the other side, and the cycle it reaches this one
+    incoming: Option<(u64, u8)>,
+    reply: Option<u8>,
+}
+
+impl LinkCable {
+    // Listen on a local port and wait for the other side to connect
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, _) = listener.accept()?;
+        Self::new(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        Self::new(TcpStream::connect(address)?)
+    }
+
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: Some(stream),
+            buf: vec![],
+            cycles: 0,
+            peer_cycles: 0,
+            last_sync: 0,
+            incoming: None,
+            reply: None,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn send(&mut self, message: Message) {
+        if let Some(stream) = self.stream.as_mut() {
+            if stream.write_all(&message.encode()).is_err() {
+                self.stream = None;
+            }
+        }
+    }
+
+    // Handle the messages received so far. With `wait`, block until at least one more byte comes in.
+    fn receive(&mut self, wait: bool) {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let mut chunk = [0; 1024];
+        let result = stream.set_nonblocking(!wait).and_then(|_| stream.read(&mut chunk));
+        match result {
+            Ok(0) => self.stream = None,
+            Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => self.stream = None,
+        }
+
+        while self.buf.len() >= MESSAGE_SIZE {
+            let message = Message::decode(&self.buf[..MESSAGE_SIZE]);
+            self.buf.drain(..MESSAGE_SIZE);
+            match message {
+                Some(message) => self.handle(message),
+                None => self.stream = None,
+            }
+        }
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::Sync(cycle) => self.peer_cycles = cycle,
+            Message::Transfer(cycle, byte) => {
+                self.peer_cycles = cycle;
+                self.incoming = Some((cycle + WINDOW, byte));
+            }
+            Message::Reply(byte) => self.reply = Some(byte),
+        }
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.send(Message::Transfer(self.cycles, byte));
+
+        while self.is_connected() && self.reply.is_none() {
+            self.receive(true);
+            // Both sides drive the clock: they get each other's byte
+            if self.incoming.take().is_some() {
+                self.send(Message::Reply(byte));
+            }
+        }
+
+        self.reply.take().unwrap_or(0xFF)
+    }
+
+    fn poll(&mut self, byte: u8) -> Option<u8> {
+        match self.incoming {
+            Some((cycle, incoming)) if cycle <= self.cycles => {
+                self.incoming = None;
+                self.send(Message::Reply(byte));
+                Some(incoming)
+            }
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self) {
+        if !self.is_connected() {
+            return;
+        }
+
+        self.cycles += 1;
+
+        // The console wasn't waiting for a transfer, nothing was shifted in or out
+        if let Some((cycle, _)) = self.incoming {
+            if cycle < self.cycles {
+                self.incoming = None;
+                self.send(Message::Reply(0xFF));
+            }
+        }
+
+        if self.cycles - self.last_sync >= SYNC_INTERVAL {
+            self.last_sync = self.cycles;
+            self.send(Message::Sync(self.cycles));
+            self.receive(false);
+        }
+
+        // Wait for the other side to catch up, unless it's waiting for this one
+        while self.is_connected() && self.incoming.is_none() && self.cycles >= self.peer_cycles + WINDOW {
+            self.receive(true);
+        }
+    }
+}
+
+// State of a virtual cable, for each of its two ends
+#[derive(Default)]
+struct Wire {
+    // M-cycles ticked
+    cycles: [u64; 2],
+    // SB of the console, while it waits for the other one to drive the clock
+    waiting: [Option<u8>; 2],
+    // Byte sent by the other console, and the cycle it arrives
+    incoming: [Option<(u64, u8)>; 2],
+}
+
+// One end of a cable between two consoles of the same process. The consoles need to run in
+// lockstep, see `LinkedConsoles`.
+pub struct VirtualCable {
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+}
+
+impl VirtualCable {
+    pub fn pair() -> (Self, Self) {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        (Self { wire: wire.clone(), side: 0 }, Self { wire, side: 1 })
+    }
+}
+
+impl SerialDevice for VirtualCable {
+    // The other console only takes part if it was waiting on its last M-cycle
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut wire = self.wire.borrow_mut();
+        let other = 1 - self.side;
+        match wire.waiting[other].take() {
+            Some(reply) => {
+                wire.incoming[other] = Some((wire.cycles[other] + WINDOW, byte));
+                reply
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn poll(&mut self, byte: u8) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        let side = self.side;
+        match wire.incoming[side] {
+            Some((cycle, incoming)) if cycle <= wire.cycles[side] => {
+                wire.incoming[side] = None;
+                Some(incoming)
+            }
+            Some(_) => None,
+            None => {
+                wire.waiting[side] = Some(byte);
+                None
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        let mut wire = self.wire.borrow_mut();
+        let side = self.side;
+        wire.cycles[side] += 1;
+        wire.waiting[side] = None;
+
+        // The console wasn't waiting for a transfer anymore, nothing was shifted in
+        if let Some((cycle, _)) = wire.incoming[side] {
+            if cycle < wire.cycles[side] {
+                wire.incoming[side] = None;
+            }
+        }
+    }
+}
+
+// Plug a virtual cable between two consoles
+pub fn connect(left: &mut Console, right: &mut Console) {
+    let (a, b) = VirtualCable::pair();
+    left.cpu.memory.serial.device = Some(Box::new(a));
+    right.cpu.memory.serial.device = Some(Box::new(b));
+}
+
+// Two consoles linked by a virtual cable, run in lockstep
+pub struct LinkedConsoles {
+    pub left: Console,
+    pub right: Console,
+}
+
+impl LinkedConsoles {
+    pub fn new(mut left: Console, mut right: Console) -> Self {
+        connect(&mut left, &mut right);
+        Self { left, right }
+    }
+
+    // One step of the left console, and as many as needed for the right one to catch up
+    pub fn step(&mut self) {
+        self.left.step();
+        self.right.run_until(self.left.cpu.cycles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::make_console;
+    use crate::serial::Serial;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+    use std::time::Duration;
+
+    // Run both ends of a cable in their own thread, the second one driven by the first. Each
+    // returns its SB once its transfer is done and the M-cycle it happened, or 0 without a
+    // transfer, and whether the cable was still connected then.
+    fn run_pair(master: u8, slave: Option<u8>) -> ((u8, u64, bool), (u8, u64, bool)) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let run = |cable: LinkCable, byte: Option<u8>, control: u8, done: Option<Receiver<()>>| {
+            let cable = Rc::new(RefCell::new(cable));
+            let mut serial = Serial::new();
+            serial.device = Some(Box::new(cable.clone()));
+            let cycles = match byte {
+                Some(byte) => {
+                    serial.data = byte;
+                    serial.set_control(control);
+                    let mut cycles = 1;
+                    while !serial.tick() {
+                        cycles += 1;
+                    }
+                    cycles
+                }
+                None => {
+                    for _ in 0..4 * WINDOW {
+                        serial.tick();
+                    }
+                    0
+                }
+            };
+            let connected = cable.borrow().is_connected();
+            // Stay plugged in until the other side is done, it would see the cable unplugged otherwise.
+            // Not forever though, in case it waits for a reply that never comes.
+            if let Some(done) = done {
+                let _ = done.recv_timeout(Duration::from_secs(5));
+            }
+            (serial.data, cycles, connected)
+        };
+
+        let (done, finished) = mpsc::channel();
+        let other = thread::spawn(move || run(LinkCable::connect(address).unwrap(), slave, 0x80, Some(finished)));
+        let (stream, _) = listener.accept().unwrap();
+        let master = run(LinkCable::new(stream).unwrap(), Some(master), 0x81, None);
+        let _ = done.send(());
+        (master, other.join().unwrap())
+    }
+
+    #[test]
+    fn test_message() {
+        for message in [Message::Sync(1 << 40), Message::Transfer(42, 0x99), Message::Reply(0x12)] {
+            assert_eq!(Message::decode(&message.encode()), Some(message));
+        }
+    }
+
+    #[test]
+    fn test_transfer() {
+        let ((master, master_cycles, connected), (slave, slave_cycles, _)) = run_pair(0x42, Some(0x99));
+        assert!(connected);
+        assert_eq!(master, 0x99);
+        assert_eq!(slave, 0x42);
+        assert_eq!(master_cycles, 1024);
+        assert_eq!(slave_cycles, WINDOW);
+    }
+
+    #[test]
+    fn test_not_waiting() {
+        // Still connected: the other side replied 0xFF, it didn't just hang up
+        let ((master, _, connected), _) = run_pair(0x42, None);
+        assert!(connected);
+        assert_eq!(master, 0xFF);
+    }
+
+    #[test]
+    fn test_linked_consoles() {
+        // Wait a bit, send 42 with the internal clock, then copy the received byte to B
+        let master = make_console(&[
+            0x0E, 0x00, 0x0D, 0x20, 0xFD, 0x3E, 0x42, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02,
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, 0xF0, 0x01, 0x47, 0x18, 0xFE,
+        ]);
+        // Send 99 with the external clock, then copy the received byte to B
+        let slave = make_console(&[
+            0x3E, 0x99, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02,
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, 0xF0, 0x01, 0x47, 0x18, 0xFE,
+        ]);
+
+        let mut linked = LinkedConsoles::new(master, slave);
+        for _ in 0..5000 {
+            linked.step();
+        }
+
+        assert_eq!(linked.left.cpu.registers.b, 0x99);
+        assert_eq!(linked.right.cpu.registers.b, 0x42);
+        assert!(linked.right.cpu.cycles >= linked.left.cpu.cycles);
+        assert!(linked.right.cpu.cycles - linked.left.cpu.cycles < 32);
+    }
+
+    #[test]
+    fn test_slave_gives_up() {
+        // Wait less, send 42 with the internal clock, then copy the received byte to B
+        let master = make_console(&[
+            0x0E, 0x40, 0x0D, 0x20, 0xFD, 0x3E, 0x42, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02,
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, 0xF0, 0x01, 0x47, 0x18, 0xFE,
+        ]);
+        // Send 99 with the external clock, stop waiting before 42 arrives, wait until it would
+        // have, then try again with 77 and copy the received byte to B
+        let slave = make_console(&[
+            0x06, 0x11, 0x3E, 0x99, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02,
+            0x0E, 0x00, 0x0D, 0x20, 0xFD, 0x3E, 0x00, 0xE0, 0x02,
+            0x0E, 0x00, 0x0D, 0x20, 0xFD, 0x3E, 0x77, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02,
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, 0xF0, 0x01, 0x47, 0x18, 0xFE,
+        ]);
+
+        let mut linked = LinkedConsoles::new(master, slave);
+        for _ in 0..5000 {
+            linked.step();
+        }
+
+        // The master got 99 all the same, but the second transfer never completes
+        assert_eq!(linked.left.cpu.registers.b, 0x99);
+        assert_eq!(linked.right.cpu.registers.b, 0x11);
+        assert_eq!(linked.right.cpu.memory.load(0xFF02) & 0x80, 0x80);
+    }
+}
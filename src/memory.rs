@@ -1,17 +1,45 @@
-use crate::cartridge::Cartridge;
+use crate::cartridge::{Cartridge, Headers};
+use crate::coverage::{Access, Coverage};
 use crate::gpu::Gpu;
 use crate::input::Input;
+use crate::serial::Serial;
 use crate::timer::Timer;
+use std::cell::Cell;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub address: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+// Access that triggered a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
 
 pub struct Memory  {
     cartridge: Cartridge, // TODO: Replace with MBC
     work_ram: Vec<u8>,
     high_ram: Vec<u8>,
     io: Vec<u8>,
-    pub serial: Vec<char>, // for debugging only
+    // Accuracy setting: block CPU accesses to VRAM during mode 3 and to OAM during modes 2-3,
+    // like the hardware does. Off by default since it hides nothing from well behaved games.
+    pub restrict_access: bool,
+    pub watchpoints: Vec<Watchpoint>,
+    // Last access that triggered a watchpoint, until the debugger takes it
+    pub watch_hit: Cell<Option<WatchHit>>,
+    // What every ROM byte was used for, when enabled
+    pub coverage: Option<Coverage>,
+    // OAM DMA in progress: source address and number of bytes copied so far
+    dma: Option<(u16, u16)>,
     pub gpu: Gpu,
     pub timer: Timer,
     pub input: Input,
+    pub serial: Serial,
 }
 
 impl Memory {
@@ -22,15 +50,85 @@ impl Memory {
             work_ram: vec![0; 0x2000], // 8 kB of RAM
             high_ram: vec![0; 0x80],   // Mapped from 0xFF80 to 0xFFF
             io: vec![0; 0x80],
-            serial: vec![],
+            restrict_access: false,
+            watchpoints: vec![],
+            watch_hit: Cell::new(None),
+            coverage: None,
+            dma: None,
             timer: Timer::new(),
             input: Input::new(),
+            serial: Serial::new(),
             cartridge,
             gpu,
         }
     }
 
+    // Load a byte like the CPU does, going through the access restrictions and watchpoints
     pub fn load(&self, address: u16) -> u8 {
+        self.cover(address, Access::Data);
+        self.read(address)
+    }
+
+    // Same as `load`, for the bytes of the instruction being executed
+    pub fn fetch(&self, address: u16, access: Access) -> u8 {
+        self.cover(address, access);
+        self.read(address)
+    }
+
+    // Advance everything but the CPU by one M-cycle (4 clock cycles)
+    pub fn tick(&mut self) {
+        self.gpu.step(4);
+
+        let mut interrupts = self.gpu.interrupts;
+        if self.timer.tick(4) {
+            interrupts |= 4;
+        }
+        if self.serial.tick() {
+            interrupts |= 8;
+        }
+        if self.input.interrupt {
+            self.input.interrupt = false;
+            interrupts |= 0x10;
+        }
+        if interrupts > 0 {
+            self.io[0x0F] |= interrupts;
+        }
+
+        // OAM DMA copies one byte per M-cycle, starting on the one after the write to 0xFF46.
+        // It is not the CPU reading, so no access restriction, watchpoint or coverage applies.
+        if let Some((source, offset)) = self.dma {
+            let byte = self.peek(source.wrapping_add(offset));
+            self.gpu.oam_store(offset, byte);
+            self.dma = if offset < 0x9F { Some((source, offset + 1)) } else { None };
+        }
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(self.cartridge.headers.rom_size));
+    }
+
+    fn cover(&self, address: u16, access: Access) {
+        if let (Some(coverage), Some(bank)) = (&self.coverage, self.rom_bank(address)) {
+            coverage.mark(bank * 0x4000 + (address as usize & 0x3FFF), access);
+        }
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        let value = match address {
+            0x8000..=0x9FFF if self.vram_blocked() => 0xFF,
+            0xFE00..=0xFE9F if self.oam_blocked() => 0xFF,
+            _ => self.peek(address),
+        };
+
+        if !self.watchpoints.is_empty() {
+            self.watch(address, value, false);
+        }
+
+        value
+    }
+
+    // Load a byte without any side effect. Used by the debugging tools and the OAM DMA.
+    pub fn peek(&self, address: u16) -> u8 {
         match address {
             0x0000..=0x7FFF => self.cartridge.mbc.load(address),
             0x8000..=0x9FFF => self.gpu.load(address),
@@ -40,6 +138,8 @@ impl Memory {
             0xFE00..=0xFE9F => self.gpu.voam[address as usize - 0xFE00],
             0xFEA0..=0xFEFF => 0, // No-op
             0xFF00 => self.input.get(),
+            0xFF01 => self.serial.data,
+            0xFF02 => self.serial.get_control(),
             0xFF04 => self.timer.div,
             0xFF05 => self.timer.tima,
             0xFF06 => self.timer.tma,
@@ -55,17 +155,27 @@ impl Memory {
             0xFF49 => self.gpu.obj_palette_1,
             0xFF4A => self.gpu.window_y,
             0xFF4B => self.gpu.window_x,
-            0xFF01..=0xFF7F => self.io[address as usize - 0xFF00],
+            0xFF03..=0xFF7F => self.io[address as usize - 0xFF00],
             0xFF80..=0xFFFF => self.high_ram[address as usize - 0xFF80],
             // _ => unimplemented!("Loading {:04X}", address),
         }
     }
 
+    // Store a byte like the CPU does, going through the access restrictions and watchpoints
     pub fn store(&mut self, address: u16, value: u8) {
-        if address == 0xFF01 {
-            unsafe { self.serial.push(std::char::from_u32_unchecked(value as u32)) }
-            return
+        if !self.watchpoints.is_empty() {
+            self.watch(address, value, true);
         }
+
+        match address {
+            0x8000..=0x9FFF if self.vram_blocked() => {}
+            0xFE00..=0xFE9F if self.oam_blocked() => {}
+            _ => self.poke(address, value),
+        }
+    }
+
+    // Store a byte, ignoring the access restrictions and watchpoints. Used by the debugging tools.
+    pub fn poke(&mut self, address: u16, value: u8) {
         match address {
             // You can't technically write to the ROM on a real game boy but it's useful in unit tests
             0x0000..=0x7FFF => self.cartridge.mbc.store(address, value),
@@ -76,27 +186,64 @@ impl Memory {
             0xFE00..=0xFE9F => self.gpu.oam_store(address - 0xFE00, value),
             0xFEA0..=0xFEFF => {} // No-op
             0xFF00 => self.input.set(value),
+            0xFF01 => self.serial.data = value,
+            0xFF02 => self.serial.set_control(value),
             0xFF04 => self.timer.div = 0,
             0xFF05 => self.timer.tima = value,
             0xFF06 => self.timer.tma = value,
             0xFF07 => self.timer.tac = value,
-            0xFF40 => self.gpu.control = value,
-            0xFF41 => self.gpu.lcd = value,
+            0xFF40 => self.gpu.set_control(value),
+            0xFF41 => self.gpu.set_status(value),
             0xFF42 => self.gpu.scroll_y = value,
             0xFF43 => self.gpu.scroll_x = value,
             0xFF44 => self.gpu.ly = 0,
-            0xFF45 => self.gpu.lyc = 0,
+            0xFF45 => self.gpu.lyc = value,
+            0xFF46 => {
+                self.io[0x46] = value;
+                self.dma = Some(((value as u16) << 8, 0));
+            }
             0xFF47 => self.gpu.bg_palette = value,
             0xFF48 => self.gpu.obj_palette_0 = value,
             0xFF49 => self.gpu.obj_palette_1 = value,
             0xFF4A => self.gpu.window_y = value,
             0xFF4B => self.gpu.window_x = value,
-            0xFF01..=0xFF7F => self.io[address as usize - 0xFF00] = value,
+            0xFF03..=0xFF7F => self.io[address as usize - 0xFF00] = value,
             0xFF80..=0xFFFF => self.high_ram[address as usize - 0xFF80] = value,
             // _ => unimplemented!("Storing {:02X} @ {:04X}", value, address),
         }
     }
 
+    fn watch(&self, address: u16, value: u8, write: bool) {
+        let hit = self.watchpoints.iter().any(|w| {
+            w.address == address && (if write { w.write } else { w.read })
+        });
+        if hit {
+            self.watch_hit.set(Some(WatchHit { address, value, write }));
+        }
+    }
+
+    // ROM bank mapped at the given address, if it's in ROM
+    pub fn rom_bank(&self, address: u16) -> Option<usize> {
+        match address {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some(self.cartridge.mbc.rom_bank()),
+            _ => None,
+        }
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.cartridge.headers
+    }
+
+    fn vram_blocked(&self) -> bool {
+        self.restrict_access && !self.gpu.vram_accessible()
+    }
+
+    // OAM is always blocked during DMA, whatever the accuracy setting
+    fn oam_blocked(&self) -> bool {
+        self.dma.is_some() || (self.restrict_access && !self.gpu.oam_accessible())
+    }
+
     // Load word at address by loading two consecutive bytes in little endian
     pub fn load16(&mut self, address: u16) -> u16 {
         let lo = self.load(address) as u16;
@@ -112,3 +259,69 @@ impl Memory {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{CartridgeType, RomOnly};
+
+    fn make_memory() -> Memory {
+        let headers = Headers { title: String::new(), title_checksum: 0, old_licensee: 0, new_licensee: [0; 2], cartridge_type: CartridgeType::RomOnly, rom_size: 0, ram_size: 0 };
+        let mbc = RomOnly::new(vec![0; 0x8000]);
+        Memory::new(Cartridge { mbc: Box::new(mbc), headers })
+    }
+
+    fn run_until_mode(memory: &mut Memory, mode: u8) {
+        while memory.gpu.lcd & 3 != mode {
+            memory.gpu.step(4);
+        }
+    }
+
+    #[test]
+    fn test_restricted_access() {
+        let mut memory = make_memory();
+        memory.restrict_access = true;
+        memory.store(0x8000, 0x12);
+        memory.store(0xFE00, 0x34);
+
+        run_until_mode(&mut memory, 2);
+        assert_eq!(memory.load(0x8000), 0x12);
+        assert_eq!(memory.load(0xFE00), 0xFF);
+        memory.store(0xFE00, 0x56);
+
+        run_until_mode(&mut memory, 3);
+        assert_eq!(memory.load(0x8000), 0xFF);
+        assert_eq!(memory.load(0xFE00), 0xFF);
+        memory.store(0x8000, 0x78);
+
+        run_until_mode(&mut memory, 0);
+        assert_eq!(memory.load(0x8000), 0x12);
+        assert_eq!(memory.load(0xFE00), 0x34);
+    }
+
+    #[test]
+    fn test_serial_transfer() {
+        let mut memory = make_memory();
+        memory.store(0xFF01, b'A');
+        memory.store(0xFF02, 0x81);
+        for _ in 0..1023 {
+            memory.tick();
+        }
+        assert_eq!(memory.load(0xFF0F) & 0x08, 0);
+        memory.tick();
+        assert_eq!(memory.load(0xFF0F) & 0x08, 0x08);
+        assert_eq!(memory.load(0xFF01), 0xFF);
+        assert_eq!(memory.load(0xFF02), 0x7F);
+        assert_eq!(memory.serial.sent, b"A");
+    }
+
+    #[test]
+    fn test_unrestricted_access() {
+        let mut memory = make_memory();
+        run_until_mode(&mut memory, 3);
+        memory.store(0x8000, 0x12);
+        memory.store(0xFE00, 0x34);
+        assert_eq!(memory.load(0x8000), 0x12);
+        assert_eq!(memory.load(0xFE00), 0x34);
+    }
+}
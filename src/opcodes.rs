@@ -8,6 +8,8 @@ use crate::register::{Flag, Register8::*, Register16::*};
 
 // Array containing all the instructions indexed by opcode.
 // Tuple format: (Instruction, number of cycles, human readable string)
+// The cycles are with branches not taken. They're for reference only: the CPU times instructions
+// by their memory accesses and internal delays.
 // Does not include the CB instructions which will be stored in a different array.
 // Idea: what if instead of the enum, the first item as fn(&mut cpu) -> () ?
 pub static OPCODES: [(Instruction, u64, &str); 0x100] = [
@@ -29,7 +31,7 @@ pub static OPCODES: [(Instruction, u64, &str); 0x100] = [
     (LdN(C),         8,  "LD C, n"),
     (Rrca,           4,  "RRCA"),
     // 1x
-    (NOP,            4,  "STOP"),
+    (Stop,           4,  "STOP"),
     (LdNN(DE),       12, "LD DE, nn"),
     (LdR16A(DE),     8, "LD [DE], A"),
     (Inc16(DE),      8,  "INC DE"),
@@ -67,8 +69,8 @@ pub static OPCODES: [(Instruction, u64, &str); 0x100] = [
     (LdSp,          12,  "LD SP, nn"),
     (LddHlA,         8,  "LDD (HL), A"),
     (Inc16(SP),      8,  "INC SP"),
-    (Inc(Pointer(HL)),   8,  "INC (HL)"),
-    (Dec(Pointer(HL)),   8,  "DEC (HL)"),
+    (Inc(Pointer(HL)),   12, "INC (HL)"),
+    (Dec(Pointer(HL)),   12, "DEC (HL)"),
     (LdHlD8,        12,  "LD n, (HL)"),
     (Scf,            4,  "SCF"),
     (Jr(Flag::C, true), 8, "JR C, nn"),
@@ -205,7 +207,7 @@ pub static OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Or(Register(E)), 4,  "OR E"),
     (Or(Register(H)), 4,  "OR H"),
     (Or(Register(L)), 4,  "OR L"),
-    (Or(Pointer(HL)), 4,  "OR (HL)"),
+    (Or(Pointer(HL)), 8,  "OR (HL)"),
     (Or(Register(A)), 4,  "OR A"),
     (Cp(Register(B)), 4,  "CP B"),
     (Cp(Register(C)), 4,  "CP C"),
@@ -213,14 +215,14 @@ pub static OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Cp(Register(E)), 4,  "CP E"),
     (Cp(Register(H)), 4,  "CP H"),
     (Cp(Register(L)), 4,  "CP L"),
-    (Cp(Pointer(HL)), 4,  "CP (HL)"),
+    (Cp(Pointer(HL)), 8,  "CP (HL)"),
     (Cp(Register(A)), 4,  "CP A"),
     // Cx
     (RetCond(Flag::Z, false), 8, "RET NZ"),
     (Pop16(BC),     12,  "POP BC"),
     (JpCond(Flag::Z, false), 12,  "JP NZ"),
     (Jp,             16, "JP"),
-    (CallCond(Flag::Z, false), 4,  "CALL NZ, d16"),
+    (CallCond(Flag::Z, false), 12, "CALL NZ, d16"),
     (Push16(BC),    16,  "PUSH BC"),
     (Add(NextByte),  8,  "ADD d8"),
     (Rst(0x00),    16,  "RST 00H"),
@@ -228,7 +230,7 @@ pub static OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Ret,           16,  "RET"),
     (JpCond(Flag::Z, true), 12,  "JP Z"),
     (NotImplemented, 4,  "NOT IMPLEMENTED YET"),
-    (CallCond(Flag::Z, true), 4,  "CALL Z, d16"),
+    (CallCond(Flag::Z, true), 12, "CALL Z, d16"),
     (Call,          24,  "CALL d16"),
     (Adc(NextByte),  8,  "ADC d8"),
     (Rst(0x08),    16,  "RST 08H"),
@@ -237,7 +239,7 @@ pub static OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Pop16(DE),     12,  "POP DE"),
     (JpCond(Flag::C, false), 12,  "JP NC"),
     (NotImplemented, 4,  "NOT IMPLEMENTED YET"),
-    (CallCond(Flag::C, false), 4,  "CALL NC, d16"),
+    (CallCond(Flag::C, false), 12, "CALL NC, d16"),
     (Push16(DE),    16,  "PUSH DE"),
     (Sub(NextByte),  8,  "SUB d8"),
     (Rst(0x10),    16,  "RST 10H"),
@@ -245,18 +247,18 @@ pub static OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Reti,          16,  "RETI"),
     (JpCond(Flag::C, true), 12,  "JP C"),
     (NotImplemented, 4,  "NOT IMPLEMENTED YET"),
-    (CallCond(Flag::C, true), 4,  "CALL C, d16"),
+    (CallCond(Flag::C, true), 12, "CALL C, d16"),
     (NotImplemented, 4,  "NOT IMPLEMENTED YET"),
-    (Sbc(NextByte),  4,  "SBC d8"),
+    (Sbc(NextByte),  8,  "SBC d8"),
     (Rst(0x18),     16,  "RST 18H"),
     // Ex
     (LdWriteIoN,    12,  "LDH (FF00+n), A"),
     (Pop16(HL),     12,  "POP HL"),
-    (LdWriteIoC,    12,  "LD (FF00+C), A"),
+    (LdWriteIoC,     8,  "LD (FF00+C), A"),
     (NotImplemented, 4,  "NOT IMPLEMENTED YET"),
     (NotImplemented, 4,  "NOT IMPLEMENTED YET"),
     (Push16(HL),    16,  "PUSH HL"),
-    (And(NextByte),          4,  "AND d8"),
+    (And(NextByte),          8,  "AND d8"),
     (Rst(0x20),     16,  "RST 20H"),
     (AddSpE8,       16,  "ADD SP, e8"),
     (JpHl,           4,  "JP HL"),
@@ -269,7 +271,7 @@ pub static OPCODES: [(Instruction, u64, &str); 0x100] = [
     // Fx
     (LdReadIoN,     12,  "LDH A, (FF00+n)"),
     (Pop16(AF),     12,  "POP AF"),
-    (LdReadIoC,     12,  "LD A, (FF00+C)"),
+    (LdReadIoC,      8,  "LD A, (FF00+C)"),
     (Di,             4,  "DI"),
     (NotImplemented, 4,  "NOT IMPLEMENTED YET"),
     (Push16(AF),    16,  "PUSH AF"),
@@ -361,7 +363,7 @@ pub static CB_OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Bit(0, Register(E)),  8, "BIT E, 0"),
     (Bit(0, Register(H)),  8, "BIT H, 0"),
     (Bit(0, Register(L)),  8, "BIT L, 0"),
-    (Bit(0, Pointer(HL)), 16, "BIT (HL), 0"),
+    (Bit(0, Pointer(HL)), 12, "BIT (HL), 0"),
     (Bit(0, Register(A)),  8, "BIT A, 0"),
     (Bit(1, Register(B)),  8, "BIT B, 1"),
     (Bit(1, Register(C)),  8, "BIT C, 1"),
@@ -369,7 +371,7 @@ pub static CB_OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Bit(1, Register(E)),  8, "BIT E, 1"),
     (Bit(1, Register(H)),  8, "BIT H, 1"),
     (Bit(1, Register(L)),  8, "BIT L, 1"),
-    (Bit(1, Pointer(HL)), 16, "BIT (HL), 1"),
+    (Bit(1, Pointer(HL)), 12, "BIT (HL), 1"),
     (Bit(1, Register(A)),  8, "BIT A, 1"),
     // 5x
     (Bit(2, Register(B)),  8, "BIT B, 2"),
@@ -378,7 +380,7 @@ pub static CB_OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Bit(2, Register(E)),  8, "BIT E, 2"),
     (Bit(2, Register(H)),  8, "BIT H, 2"),
     (Bit(2, Register(L)),  8, "BIT L, 2"),
-    (Bit(2, Pointer(HL)), 16, "BIT (HL), 2"),
+    (Bit(2, Pointer(HL)), 12, "BIT (HL), 2"),
     (Bit(2, Register(A)),  8, "BIT A, 2"),
     (Bit(3, Register(B)),  8, "BIT B, 3"),
     (Bit(3, Register(C)),  8, "BIT C, 3"),
@@ -386,7 +388,7 @@ pub static CB_OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Bit(3, Register(E)),  8, "BIT E, 3"),
     (Bit(3, Register(H)),  8, "BIT H, 3"),
     (Bit(3, Register(L)),  8, "BIT L, 3"),
-    (Bit(3, Pointer(HL)), 16, "BIT (HL), 3"),
+    (Bit(3, Pointer(HL)), 12, "BIT (HL), 3"),
     (Bit(3, Register(A)),  8, "BIT A, 3"),
     // 6x
     (Bit(4, Register(B)),  8, "BIT B, 4"),
@@ -395,7 +397,7 @@ pub static CB_OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Bit(4, Register(E)),  8, "BIT E, 4"),
     (Bit(4, Register(H)),  8, "BIT H, 4"),
     (Bit(4, Register(L)),  8, "BIT L, 4"),
-    (Bit(4, Pointer(HL)), 16, "BIT (HL), 4"),
+    (Bit(4, Pointer(HL)), 12, "BIT (HL), 4"),
     (Bit(4, Register(A)),  8, "BIT A, 4"),
     (Bit(5, Register(B)),  8, "BIT B, 5"),
     (Bit(5, Register(C)),  8, "BIT C, 5"),
@@ -403,7 +405,7 @@ pub static CB_OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Bit(5, Register(E)),  8, "BIT E, 5"),
     (Bit(5, Register(H)),  8, "BIT H, 5"),
     (Bit(5, Register(L)),  8, "BIT L, 5"),
-    (Bit(5, Pointer(HL)), 16, "BIT (HL), 5"),
+    (Bit(5, Pointer(HL)), 12, "BIT (HL), 5"),
     (Bit(5, Register(A)),  8, "BIT A, 5"),
     // 7x
     (Bit(6, Register(B)),  8, "BIT B, 6"),
@@ -412,7 +414,7 @@ pub static CB_OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Bit(6, Register(E)),  8, "BIT E, 6"),
     (Bit(6, Register(H)),  8, "BIT H, 6"),
     (Bit(6, Register(L)),  8, "BIT L, 6"),
-    (Bit(6, Pointer(HL)), 16, "BIT (HL), 6"),
+    (Bit(6, Pointer(HL)), 12, "BIT (HL), 6"),
     (Bit(6, Register(A)),  8, "BIT A, 6"),
     (Bit(7, Register(B)),  8, "BIT B, 7"),
     (Bit(7, Register(C)),  8, "BIT C, 7"),
@@ -420,7 +422,7 @@ pub static CB_OPCODES: [(Instruction, u64, &str); 0x100] = [
     (Bit(7, Register(E)),  8, "BIT E, 7"),
     (Bit(7, Register(H)),  8, "BIT H, 7"),
     (Bit(7, Register(L)),  8, "BIT L, 7"),
-    (Bit(7, Pointer(HL)), 16, "BIT (HL), 7"),
+    (Bit(7, Pointer(HL)), 12, "BIT (HL), 7"),
     (Bit(7, Register(A)),  8, "BIT A, 7"),
     // 8x
     (Res(0, Register(B)),  8, "RES B, 0"),
@@ -0,0 +1,261 @@
+// Output palettes used to turn the 4 DMG shades into RGB colors.
+// Each layer (background/window, OBJ0 and OBJ1) can have its own palette, which is what the
+// Game Boy Color does when running original Game Boy games.
+
+use crate::cartridge::Headers;
+
+pub type Rgb = (u8, u8, u8);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette(pub [Rgb; 4]);
+
+impl Palette {
+    // Parse a palette from 4 comma separated hex colors, lightest first. E.g: "E0F8D0,88C070,346856,081820"
+    pub fn from_hex(s: &str) -> Option<Self> {
+        let colors = s.split(',').map(|c| {
+            let c = c.trim().trim_start_matches('#');
+            if c.len() != 6 {
+                return None;
+            }
+            u32::from_str_radix(c, 16).ok().map(rgb)
+        }).collect::<Option<Vec<Rgb>>>()?;
+
+        match colors.as_slice() {
+            &[c0, c1, c2, c3] => Some(Palette([c0, c1, c2, c3])),
+            _ => None,
+        }
+    }
+}
+
+const fn rgb(color: u32) -> Rgb {
+    ((color >> 16) as u8, (color >> 8) as u8, color as u8)
+}
+
+const fn palette(colors: [u32; 4]) -> Palette {
+    Palette([rgb(colors[0]), rgb(colors[1]), rgb(colors[2]), rgb(colors[3])])
+}
+
+pub const DMG_GREEN: Palette = palette([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]);
+pub const POCKET: Palette = palette([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]);
+pub const LIGHT: Palette = palette([0x00B581, 0x009A71, 0x00694A, 0x004F3B]);
+pub const BGB: Palette = palette([0xE0F8D0, 0x88C070, 0x346856, 0x081820]);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palettes {
+    pub bg: Palette,
+    pub obj0: Palette,
+    pub obj1: Palette,
+}
+
+impl Palettes {
+    pub fn uniform(palette: Palette) -> Self {
+        Self { bg: palette, obj0: palette, obj1: palette }
+    }
+
+    // Palettes picked by the CGB boot ROM for a DMG game. Only games licensed by Nintendo get
+    // their own, recognized by the checksum of their title, plus its 4th letter when several
+    // titles share a checksum. Other games get the default palettes.
+    // https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+    pub fn cgb_for_headers(headers: &Headers) -> Self {
+        let index = if headers.is_nintendo() { cgb_title_index(headers) } else { 0 };
+        let (obj0, obj1, bg) = CGB_PALETTE_OFFSETS[CGB_COMBINATIONS[index] as usize];
+        Self { bg: cgb_palette(bg), obj0: cgb_palette(obj0), obj1: cgb_palette(obj1) }
+    }
+}
+
+// Index of the game in the boot ROM tables, 0 when it isn't there
+fn cgb_title_index(headers: &Headers) -> usize {
+    let letter = headers.title.as_bytes().get(3).copied().unwrap_or(0);
+    (1..CGB_CHECKSUMS.len())
+        .find(|&i| CGB_CHECKSUMS[i] == headers.title_checksum
+            && (i < CGB_SHARED_CHECKSUMS || CGB_FOURTH_LETTERS[i - CGB_SHARED_CHECKSUMS] == letter))
+        .unwrap_or(0)
+}
+
+// 4 colors from the boot ROM, which stores them as RGB555 with red in the low bits
+fn cgb_palette(offset: usize) -> Palette {
+    let color = |n: usize| {
+        let color = CGB_COLORS[offset + n];
+        let component = |shift: u16| {
+            let c = (color >> shift & 0x1F) as u8;
+            c << 3 | c >> 2
+        };
+        (component(0), component(5), component(10))
+    };
+    Palette([color(0), color(1), color(2), color(3)])
+}
+
+// The tables below come from the CGB boot ROM, as disassembled by SameBoy.
+// https://github.com/LIJI32/SameBoy/blob/master/BootROMs/cgb_boot.asm
+
+// Title checksums, the first entry being the default palettes. From CGB_SHARED_CHECKSUMS on,
+// checksums are shared by several games and also need the 4th letter of the title to match.
+const CGB_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+const CGB_SHARED_CHECKSUMS: usize = 65;
+const CGB_FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Palette combination of each game
+const CGB_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+    46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// Offsets in CGB_COLORS of the OBJ0, OBJ1 and BG palettes of each combination. Most are
+// aligned on a palette, a few start in the middle of one.
+const CGB_PALETTE_OFFSETS: [(usize, usize, usize); 51] = [
+    (16, 16, 116), (72, 72, 72), (80, 80, 80), (96, 96, 96), (36, 36, 36), (0, 0, 0), (108, 108, 108), (20, 20, 20),
+    (48, 48, 48), (104, 104, 104), (64, 32, 32), (16, 112, 112), (16, 8, 8), (12, 16, 16), (16, 116, 116), (112, 16, 112),
+    (8, 68, 8), (64, 64, 32), (16, 16, 28), (16, 16, 72), (16, 16, 80), (76, 76, 36), (15, 15, 44), (68, 68, 8),
+    (16, 16, 8), (16, 16, 12), (112, 112, 0), (12, 12, 0), (0, 0, 4), (72, 88, 72), (80, 88, 80), (96, 88, 96),
+    (64, 88, 32), (68, 16, 52), (111, 0, 56), (111, 16, 60), (76, 91, 36), (64, 112, 40), (16, 92, 112), (68, 88, 8),
+    (16, 0, 8), (16, 112, 12), (112, 12, 0), (12, 112, 16), (84, 112, 16), (12, 112, 0), (100, 12, 112), (0, 112, 32),
+    (16, 12, 112), (112, 12, 24), (16, 112, 116),
+];
+
+const CGB_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, 0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000, 0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, 0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000, 0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, 0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, 0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, 0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000, 0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000, 0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, 0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000, 0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, 0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, 0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000, 0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, 0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+impl Default for Palettes {
+    fn default() -> Self {
+        Self::uniform(DMG_GREEN)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preset {
+    DmgGreen,
+    Pocket,
+    Light,
+    Bgb,
+    Cgb, // Depends on the game title, like the CGB boot ROM
+}
+
+impl Preset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dmg" => Some(Preset::DmgGreen),
+            "pocket" => Some(Preset::Pocket),
+            "light" => Some(Preset::Light),
+            "bgb" => Some(Preset::Bgb),
+            "cgb" => Some(Preset::Cgb),
+            _ => None,
+        }
+    }
+
+    pub fn palettes(self, headers: &Headers) -> Palettes {
+        match self {
+            Preset::DmgGreen => Palettes::uniform(DMG_GREEN),
+            Preset::Pocket => Palettes::uniform(POCKET),
+            Preset::Light => Palettes::uniform(LIGHT),
+            Preset::Bgb => Palettes::uniform(BGB),
+            Preset::Cgb => Palettes::cgb_for_headers(headers),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorCorrection {
+    None,
+    // Approximates the washed out colors of the CGB LCD
+    Cgb,
+}
+
+impl ColorCorrection {
+    pub fn apply(self, color: Rgb) -> Rgb {
+        match self {
+            ColorCorrection::None => color,
+            ColorCorrection::Cgb => {
+                // Same curve as Gambatte, working on the 5 bits CGB components
+                let (r, g, b) = ((color.0 >> 3) as u32, (color.1 >> 3) as u32, (color.2 >> 3) as u32);
+                let r2 = (r * 13 + g * 2 + b) >> 1;
+                let g2 = (g * 3 + b) << 1;
+                let b2 = (r * 3 + g * 2 + b * 11) >> 1;
+                (r2 as u8, g2 as u8, b2 as u8)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::CartridgeType;
+
+    #[test]
+    fn test_from_hex() {
+        assert_eq!(Palette::from_hex("E0F8D0,88C070,346856,081820"), Some(BGB));
+        assert_eq!(Palette::from_hex("#e0f8d0, #88c070, #346856, #081820"), Some(BGB));
+        assert_eq!(Palette::from_hex("E0F8D0,88C070,346856"), None);
+        assert_eq!(Palette::from_hex("E0F8D0,88C070,346856,nope"), None);
+    }
+
+    #[test]
+    fn test_cgb_for_headers() {
+        let headers = |title: &str, old_licensee: u8, new_licensee: &[u8; 2]| {
+            let mut bytes = title.as_bytes().to_vec();
+            bytes.resize(16, 0);
+            Headers {
+                title: title.into(),
+                title_checksum: bytes.iter().fold(0, |sum, &c| sum.wrapping_add(c)),
+                old_licensee,
+                new_licensee: *new_licensee,
+                cartridge_type: CartridgeType::MBC3,
+                rom_size: 0,
+                ram_size: 0,
+            }
+        };
+
+        let red = palette([0xFFFFFF, 0xFF8484, 0x943939, 0x000000]);
+        let green = palette([0xFFFFFF, 0x7BFF31, 0x008400, 0x000000]);
+        let blue = palette([0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000]);
+        let default = Palettes { bg: palette([0xFFFFFF, 0x7BFF31, 0x0063C6, 0x000000]), obj0: red, obj1: red };
+
+        assert_eq!(Palettes::cgb_for_headers(&headers("POKEMON RED", 0x01, b"00")), Palettes { bg: red, obj0: green, obj1: red });
+        assert_eq!(Palettes::cgb_for_headers(&headers("POKEMON BLUE", 0x33, b"01")), Palettes { bg: blue, obj0: red, obj1: blue });
+        // Not licensed by Nintendo
+        assert_eq!(Palettes::cgb_for_headers(&headers("POKEMON RED", 0x33, b"08")), default);
+        assert_eq!(Palettes::cgb_for_headers(&headers("UNKNOWN", 0x01, b"00")), default);
+
+        // Both share the checksum 0x46, the 4th letter tells them apart
+        assert_eq!(cgb_title_index(&headers("SUPER MARIOLAND", 0x01, b"00")), 66);
+        assert_eq!(cgb_title_index(&headers("SUPRE MARIOLAND", 0x01, b"00")), 80);
+        assert_eq!(cgb_title_index(&headers("SUEPR MARIOLAND", 0x01, b"00")), 0);
+        let mario = Palettes::cgb_for_headers(&headers("SUPER MARIOLAND", 0x01, b"00"));
+        assert_eq!(mario.bg, palette([0xB5B5FF, 0xFFFF94, 0xAD5A42, 0x000000]));
+        assert_eq!(mario.obj0, palette([0x000000, 0xFFFFFF, 0xFF8484, 0x943939]));
+    }
+
+    #[test]
+    fn test_color_correction() {
+        assert_eq!(ColorCorrection::None.apply((0xFF, 0x80, 0x00)), (0xFF, 0x80, 0x00));
+        assert_eq!(ColorCorrection::Cgb.apply((0xFF, 0xFF, 0xFF)), (0xF8, 0xF8, 0xF8));
+        assert_eq!(ColorCorrection::Cgb.apply((0, 0, 0)), (0, 0, 0));
+    }
+}
@@ -0,0 +1,121 @@
+// Minimal PNG encoder for 8-bit grayscale images. The pixels aren't compressed: the zlib
+// stream is made of stored deflate blocks, which keeps this small and dependency free.
+// https://www.w3.org/TR/png/
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// Largest stored deflate block
+const MAX_BLOCK: usize = 0xFFFF;
+
+pub fn encode_grayscale(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height, "Wrong number of pixels");
+
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, grayscale, deflate, no filter, no interlace
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+    // Every row starts with its filter type, none here
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width.max(1)).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate, 32K window, no preset dictionary, fastest
+    let mut zlib = vec![0x78, 0x01];
+
+    let blocks = data.chunks(MAX_BLOCK).collect::<Vec<_>>();
+    if blocks.is_empty() {
+        zlib.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    for (n, block) in blocks.iter().enumerate() {
+        let last = n == blocks.len() - 1;
+        let len = block.len() as u16;
+        zlib.push(last as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+
+    zlib.extend_from_slice(&adler32(data).to_be_bytes());
+    zlib
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_encode() {
+        let png = encode_grayscale(2, 2, &[0x00, 0x55, 0xAA, 0xFF]);
+        assert_eq!(png[..8], SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..29], [0, 0, 0, 2, 0, 0, 0, 2, 8, 0, 0, 0, 0]);
+
+        // IDAT: zlib header, a single stored block with the filtered rows, and the Adler-32
+        let idat = &png[33..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        let raw = [0, 0x00, 0x55, 0, 0xAA, 0xFF];
+        let mut zlib = vec![0x78, 0x01, 0x01, 0x06, 0x00, 0xF9, 0xFF];
+        zlib.extend_from_slice(&raw);
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+        assert_eq!(&idat[8..8 + zlib.len()], zlib.as_slice());
+
+        assert_eq!(png[png.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn test_blocks() {
+        let data = vec![7; MAX_BLOCK + 10];
+        let zlib = zlib_stored(&data);
+        assert_eq!(zlib[2], 0);
+        let second = 2 + 5 + MAX_BLOCK;
+        assert_eq!(zlib[second..second + 5], [1, 10, 0, 0xF5, 0xFF]);
+        assert_eq!(zlib.len(), 2 + 2 * 5 + data.len() + 4);
+    }
+}
@@ -0,0 +1,377 @@
+// Game Boy Printer, plugged into the link port. The game sends packets:
+//   0x88 0x33, command, compression, length (16-bit LE), data, checksum (16-bit LE), 0x00 0x00
+// The printer answers 0x00 to every byte but the last two: 0x81 (it's alive) and its status.
+// https://gbdev.io/pandocs/Gameboy_Printer.html
+
+use crate::png;
+use crate::serial::SerialDevice;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// Commands
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const STATUS: u8 = 0x0F;
+
+// Status bits
+const CHECKSUM_ERROR: u8 = 0x01;
+const BUSY: u8 = 0x02;
+const FULL: u8 = 0x04;
+const UNPROCESSED: u8 = 0x08;
+const PACKET_ERROR: u8 = 0x10;
+
+const ALIVE: u8 = 0x81;
+
+// Images are 20 tiles wide, and the printer holds up to 9 bands of 2 rows of tiles
+pub const WIDTH: usize = 160;
+const TILE_ROW_BYTES: usize = 20 * 16;
+const BUFFER_SIZE: usize = 9 * 2 * TILE_ROW_BYTES;
+
+// M-cycles spent printing every line of pixels
+const LINE_CYCLES: u32 = 1024;
+
+// Output gray levels of the 4 shades, lightest first
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    // Finished sheets, 160 pixels wide, one gray level per pixel
+    pub sheets: Vec<Vec<u8>>,
+    // Where sheets are saved as PNG, if anywhere
+    directory: Option<PathBuf>,
+    // Why the last sheet couldn't be saved. It's still in `sheets`.
+    pub error: Option<io::Error>,
+    state: State,
+    status: u8,
+    // Packet being received
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    // Decompressed tile data waiting to be printed
+    buffer: Vec<u8>,
+    // Sheet being printed, until a bottom margin ends it
+    sheet: Vec<u8>,
+    // M-cycles left until the printer is done printing
+    printing: u32,
+}
+
+impl Printer {
+    pub fn new(directory: Option<PathBuf>) -> Self {
+        Self {
+            sheets: vec![],
+            directory,
+            error: None,
+            state: State::Magic1,
+            status: 0,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: vec![],
+            checksum: 0,
+            received_checksum: 0,
+            buffer: vec![],
+            sheet: vec![],
+            printing: 0,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        self.state = match self.state {
+            State::Magic1 if byte == 0x88 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == 0x33 => {
+                self.data.clear();
+                self.checksum = 0;
+                State::Command
+            }
+            State::Magic2 if byte == 0x88 => State::Magic2,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                self.add_checksum(byte);
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 1 != 0;
+                self.add_checksum(byte);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as usize;
+                self.add_checksum(byte);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.add_checksum(byte);
+                if self.length > 0 { State::Data } else { State::ChecksumLow }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.add_checksum(byte);
+                if self.data.len() == self.length { State::ChecksumLow } else { State::Data }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                // Processed now, so that the status sent at the end of the packet is up to date
+                self.process();
+                State::Alive
+            }
+            State::Alive => State::Status,
+            State::Status => State::Magic1,
+        };
+    }
+
+    fn add_checksum(&mut self, byte: u8) {
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+    }
+
+    fn process(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !(CHECKSUM_ERROR | PACKET_ERROR);
+
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            PRINT if self.data.len() >= 4 => {
+                // Number of sheets, margins, palette, exposure. The exposure is ignored.
+                let margins = self.data[1];
+                let palette = self.data[2];
+                self.print(margins, palette);
+            }
+            DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                let room = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+                if !self.buffer.is_empty() {
+                    self.status |= UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= FULL;
+                }
+            }
+            STATUS => {}
+            _ => self.status |= PACKET_ERROR,
+        }
+    }
+
+    // Margins: lines fed before (upper nibble) and after (lower nibble) printing. Sheets are
+    // printed in several parts with no margin in between, a bottom margin means it's done.
+    fn print(&mut self, margins: u8, palette: u8) {
+        // Some games leave the palette at 0, the printer uses the default one then
+        let palette = if palette == 0 { 0xE4 } else { palette };
+
+        let lines = self.buffer.len() / TILE_ROW_BYTES * 8;
+        for line in 0..lines {
+            let row = line / 8 * TILE_ROW_BYTES + line % 8 * 2;
+            for x in 0..WIDTH {
+                let address = row + x / 8 * 16;
+                let bit = 7 - x % 8;
+                let low = (self.buffer[address] >> bit) & 1;
+                let high = (self.buffer[address + 1] >> bit) & 1;
+                let color = (high << 1) | low;
+                let shade = (palette >> (color * 2)) & 3;
+                self.sheet.push(SHADES[shade as usize]);
+            }
+        }
+
+        self.buffer.clear();
+        self.status = (self.status & !(UNPROCESSED | FULL)) | BUSY;
+        self.printing = (lines as u32).max(1) * LINE_CYCLES;
+
+        if margins & 0x0F != 0 && !self.sheet.is_empty() {
+            let sheet = std::mem::take(&mut self.sheet);
+            if let Err(error) = self.save(&sheet) {
+                self.error = Some(error);
+            }
+            self.sheets.push(sheet);
+        }
+    }
+
+    fn save(&self, sheet: &[u8]) -> io::Result<()> {
+        let directory = match &self.directory {
+            Some(directory) => directory,
+            None => return Ok(()),
+        };
+
+        // First free name, to keep the sheets of previous sessions
+        let path = (1..)
+            .map(|n| directory.join(format!("print-{:03}.png", n)))
+            .find(|path| !path.exists())
+            .unwrap();
+        let png = png::encode_grayscale(WIDTH, sheet.len() / WIDTH, sheet);
+        fs::write(&path, png).map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let reply = match self.state {
+            State::Alive => ALIVE,
+            State::Status => self.status,
+            _ => 0x00,
+        };
+        self.receive(byte);
+        reply
+    }
+
+    fn tick(&mut self) {
+        if self.printing > 0 {
+            self.printing -= 1;
+            if self.printing == 0 {
+                self.status &= !BUSY;
+            }
+        }
+    }
+}
+
+// Run-length encoding: a control byte with bit 7 set repeats the next byte (control & 0x7F) + 2
+// times, otherwise the next control + 1 bytes are copied as they are
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![];
+    let mut i = 0;
+
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if let Some(&byte) = data.get(i) {
+                output.extend(std::iter::repeat(byte).take((control & 0x7F) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let length = data.len() as u16;
+        let mut bytes = vec![command, compressed as u8];
+        bytes.extend_from_slice(&length.to_le_bytes());
+        bytes.extend_from_slice(data);
+        let checksum = bytes.iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+
+        let mut packet = vec![0x88, 0x33];
+        packet.extend(bytes);
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(&[0x00, 0x00]);
+        packet
+    }
+
+    // Returns the last two replies: alive and status
+    fn send(printer: &mut Printer, packet: &[u8]) -> (u8, u8) {
+        let replies = packet.iter().map(|&byte| printer.transfer(byte)).collect::<Vec<_>>();
+        assert!(replies[..replies.len() - 2].iter().all(|&reply| reply == 0));
+        (replies[replies.len() - 2], replies[replies.len() - 1])
+    }
+
+    #[test]
+    fn test_packets() {
+        let mut printer = Printer::new(None);
+        assert_eq!(send(&mut printer, &packet(INIT, false, &[])), (ALIVE, 0));
+        assert_eq!(send(&mut printer, &packet(STATUS, false, &[])), (ALIVE, 0));
+
+        assert_eq!(send(&mut printer, &packet(DATA, false, &[0; TILE_ROW_BYTES * 2])), (ALIVE, UNPROCESSED));
+        // End of data
+        assert_eq!(send(&mut printer, &packet(DATA, false, &[])), (ALIVE, UNPROCESSED));
+
+        let mut bad = packet(STATUS, false, &[]);
+        bad[6] ^= 1;
+        assert_eq!(send(&mut printer, &bad), (ALIVE, UNPROCESSED | CHECKSUM_ERROR));
+        assert_eq!(send(&mut printer, &packet(0x42, false, &[])), (ALIVE, UNPROCESSED | PACKET_ERROR));
+
+        assert_eq!(send(&mut printer, &packet(INIT, false, &[])), (ALIVE, 0));
+        assert!(printer.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_print() {
+        let mut printer = Printer::new(None);
+        // First tile of the first line: colors 0, 1, 2, 3, 0, 1, 2, 3, the rest is color 3
+        let mut data = vec![0xFF; TILE_ROW_BYTES * 2];
+        data[0] = 0b0101_0101;
+        data[1] = 0b0011_0011;
+        send(&mut printer, &packet(DATA, false, &data));
+
+        // Inverted palette, no bottom margin: the sheet isn't done yet
+        assert_eq!(send(&mut printer, &packet(PRINT, false, &[1, 0x10, 0x1B, 0x40])), (ALIVE, BUSY));
+        assert!(printer.sheets.is_empty());
+        send(&mut printer, &packet(DATA, false, &data));
+        send(&mut printer, &packet(PRINT, false, &[1, 0x03, 0x1B, 0x40]));
+
+        assert_eq!(printer.sheets.len(), 1);
+        let sheet = &printer.sheets[0];
+        assert_eq!(sheet.len(), WIDTH * 32);
+        assert_eq!(sheet[..8], [0x00, 0x55, 0xAA, 0xFF, 0x00, 0x55, 0xAA, 0xFF]);
+        assert!(sheet[8..WIDTH].iter().all(|&pixel| pixel == 0xFF));
+        assert_eq!(sheet[WIDTH * 16], 0x00);
+
+        for _ in 0..16 * LINE_CYCLES {
+            printer.tick();
+        }
+        assert_eq!(send(&mut printer, &packet(STATUS, false, &[])), (ALIVE, 0));
+    }
+
+    #[test]
+    fn test_save_error() {
+        let directory = std::env::temp_dir().join("yeeboy-missing").join("prints");
+        let mut printer = Printer::new(Some(directory));
+        send(&mut printer, &packet(DATA, false, &[0; TILE_ROW_BYTES * 2]));
+        send(&mut printer, &packet(PRINT, false, &[1, 0x03, 0xE4, 0x40]));
+        assert!(printer.error.is_some());
+        assert_eq!(printer.sheets.len(), 1);
+    }
+
+    #[test]
+    fn test_decompress() {
+        assert_eq!(decompress(&[0x81, 0xAA, 0x02, 1, 2, 3, 0x80, 0x55]), vec![0xAA, 0xAA, 0xAA, 1, 2, 3, 0x55, 0x55]);
+
+        let mut printer = Printer::new(None);
+        let mut compressed = vec![];
+        for _ in 0..TILE_ROW_BYTES * 2 / 128 {
+            compressed.extend_from_slice(&[0xFE, 0x12]);
+        }
+        compressed.extend_from_slice(&[0x03, 1, 2, 3, 4]);
+        send(&mut printer, &packet(DATA, true, &compressed));
+        assert_eq!(printer.buffer.len(), TILE_ROW_BYTES * 2 / 128 * 128 + 4);
+        assert_eq!(printer.buffer[..2], [0x12, 0x12]);
+        assert_eq!(printer.buffer[printer.buffer.len() - 4..], [1, 2, 3, 4]);
+    }
+}
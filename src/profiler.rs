@@ -0,0 +1,209 @@
+// Counts executed instructions and cycles per (bank, pc) and per function. Functions are
+// delimited by the shadow call stack: every cycle goes to the innermost function being
+// executed, and to all the functions below it in the stack for inclusive counts.
+
+use crate::cpu::Cpu;
+use crate::symbols::Symbols;
+use std::collections::{HashMap, HashSet};
+
+// Code running outside of any call is attributed to the cartridge entry point
+const ROOT: (usize, u16) = (0, 0x0100);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Counts {
+    fn add(&mut self, instructions: u64, cycles: u64) {
+        self.instructions += instructions;
+        self.cycles += cycles;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Profiler {
+    addresses: HashMap<(usize, u16), Counts>,
+    // Functions from the outermost to the innermost, to the counts spent in the innermost
+    stacks: HashMap<Vec<(usize, u16)>, Counts>,
+    total: Counts,
+    // State of the step being profiled
+    location: (usize, u16),
+    halted: bool,
+    path: Vec<(usize, u16)>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Record where the CPU is before a step...
+    pub fn start_step(&mut self, cpu: &Cpu) {
+        let bank = |address: u16| cpu.memory.rom_bank(address).unwrap_or(0);
+
+        self.location = (bank(cpu.pc), cpu.pc);
+        self.halted = cpu.halted || cpu.stopped;
+        self.path.clear();
+        self.path.push(ROOT);
+        self.path.extend(cpu.call_stack.frames().iter().map(|f| (bank(f.target), f.target)));
+    }
+
+    // ...and how long it took. A halted or stopped CPU doesn't execute instructions.
+    pub fn end_step(&mut self, cycles: u64) {
+        let instructions = if self.halted { 0 } else { 1 };
+
+        self.total.add(instructions, cycles);
+        self.addresses.entry(self.location).or_default().add(instructions, cycles);
+        match self.stacks.get_mut(self.path.as_slice()) {
+            Some(counts) => counts.add(instructions, cycles),
+            None => {
+                let mut counts = Counts::default();
+                counts.add(instructions, cycles);
+                self.stacks.insert(self.path.clone(), counts);
+            }
+        }
+    }
+
+    pub fn total(&self) -> Counts {
+        self.total
+    }
+
+    pub fn address(&self, bank: usize, address: u16) -> Counts {
+        self.addresses.get(&(bank, address)).copied().unwrap_or_default()
+    }
+
+    // Self and inclusive counts of every function, by entry point
+    pub fn functions(&self) -> HashMap<(usize, u16), (Counts, Counts)> {
+        let mut functions: HashMap<(usize, u16), (Counts, Counts)> = HashMap::new();
+
+        for (path, counts) in &self.stacks {
+            let innermost = *path.last().unwrap();
+            functions.entry(innermost).or_default().0.add(counts.instructions, counts.cycles);
+
+            // Recursive functions only count once
+            let unique = path.iter().collect::<HashSet<_>>();
+            for &function in unique {
+                functions.entry(function).or_default().1.add(counts.instructions, counts.cycles);
+            }
+        }
+
+        functions
+    }
+
+    // Hottest functions and instructions, by self cycles
+    pub fn report(&self, symbols: &Symbols, limit: usize) -> String {
+        let name = |(bank, address): (usize, u16)| match symbols.describe(bank, address) {
+            Some(name) => format!("{:02X}:{:04X} {}", bank, address, name),
+            None => format!("{:02X}:{:04X}", bank, address),
+        };
+        let percent = |cycles: u64| 100.0 * cycles as f64 / self.total.cycles.max(1) as f64;
+
+        let mut lines = vec![
+            format!("{} instructions, {} cycles", self.total.instructions, self.total.cycles),
+            String::new(),
+            format!("{:>12} {:>6} {:>12} {:>6} {:>12}  Function", "Self cycles", "%", "Total cycles", "%", "Instructions"),
+        ];
+
+        let mut functions = self.functions().into_iter().collect::<Vec<_>>();
+        functions.sort_by(|a, b| (b.1).0.cycles.cmp(&(a.1).0.cycles).then(a.0.cmp(&b.0)));
+        for (function, (own, inclusive)) in functions.into_iter().take(limit) {
+            lines.push(format!(
+                "{:>12} {:>6.2} {:>12} {:>6.2} {:>12}  {}",
+                own.cycles, percent(own.cycles), inclusive.cycles, percent(inclusive.cycles), own.instructions, name(function),
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push(format!("{:>12} {:>6} {:>12}  Address", "Cycles", "%", "Executions"));
+
+        let mut addresses = self.addresses.iter().collect::<Vec<_>>();
+        addresses.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+        for (&address, counts) in addresses.into_iter().take(limit) {
+            lines.push(format!(
+                "{:>12} {:>6.2} {:>12}  {}",
+                counts.cycles, percent(counts.cycles), counts.instructions, name(address),
+            ));
+        }
+
+        lines.join("\n")
+    }
+
+    // One "outer;inner cycles" line per call stack, as expected by flamegraph.pl and inferno
+    pub fn folded_stacks(&self, symbols: &Symbols) -> String {
+        let name = |&(bank, address): &(usize, u16)| match symbols.describe(bank, address) {
+            Some(name) => name,
+            None => format!("{:02X}:{:04X}", bank, address),
+        };
+
+        let mut lines = self.stacks.iter()
+            .map(|(path, counts)| {
+                let path = path.iter().map(name).collect::<Vec<String>>().join(";");
+                format!("{} {}", path, counts.cycles)
+            })
+            .collect::<Vec<String>>();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::{make_console, Console};
+
+    // 0100: CALL 0110
+    // 0103: JR 0100
+    // 0110: NOP
+    // 0111: RET
+    fn make_profiled_console() -> Console {
+        let mut code = vec![0; 0x12];
+        code[0..5].copy_from_slice(&[0xCD, 0x10, 0x01, 0x18, 0xFB]);
+        code[0x11] = 0xC9;
+        let mut console = make_console(&code);
+        console.profiler = Some(Profiler::new());
+        console
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut console = make_profiled_console();
+        for _ in 0..8 {
+            console.step();
+        }
+
+        let profiler = console.profiler.as_ref().unwrap();
+        let total = profiler.total();
+        assert_eq!(total.instructions, 8);
+        assert_eq!(profiler.address(0, 0x110), Counts { instructions: 2, cycles: 8 });
+
+        let functions = profiler.functions();
+        let (own, inclusive) = functions[&(0, 0x110)];
+        assert_eq!(own, Counts { instructions: 4, cycles: 40 });
+        assert_eq!(inclusive, own);
+        let (own, inclusive) = functions[&ROOT];
+        assert_eq!(own, Counts { instructions: 4, cycles: total.cycles - 40 });
+        assert_eq!(inclusive, total);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut console = make_profiled_console();
+        for _ in 0..8 {
+            console.step();
+        }
+
+        let symbols = Symbols::parse("00:0100 Main\n00:0110 Func\n");
+        let profiler = console.profiler.as_ref().unwrap();
+
+        let total = profiler.total().cycles;
+        assert_eq!(profiler.folded_stacks(&symbols), format!("Main {}\nMain;Func 40\n", total - 40));
+
+        let report = profiler.report(&symbols, 10);
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], format!("8 instructions, {} cycles", total));
+        assert!(lines[3].ends_with("00:0100 Main"), "{}", lines[3]);
+        assert!(lines[4].ends_with("00:0110 Func"), "{}", lines[4]);
+    }
+}
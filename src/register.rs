@@ -39,6 +39,12 @@ pub struct Registers {
 use Register8::*;
 use Register16::*;
 
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Registers {
     pub fn new() -> Self {
         Self {
@@ -0,0 +1,228 @@
+// Serial port: SB (0xFF01) and SC (0xFF02). A transfer shifts the 8 bits of SB out, MSB first,
+// while the bits sent by the other end of the link cable are shifted in. Whoever drives the
+// clock starts the transfer: the console with the internal clock, the device with the external one.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Peripheral plugged into the link port
+pub trait SerialDevice {
+    // The console drives the clock: the device receives `byte` and sends back the returned one
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    // The device drives the clock: the byte it sends when it starts a transfer, in exchange for
+    // `byte`. Called every M-cycle while the console waits for one.
+    fn poll(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+
+    // Called every M-cycle, before anything else
+    fn tick(&mut self) {}
+}
+
+// Shared devices, to look at them while they're plugged in
+impl<T: SerialDevice> SerialDevice for Rc<RefCell<T>> {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.borrow_mut().transfer(byte)
+    }
+
+    fn poll(&mut self, byte: u8) -> Option<u8> {
+        self.borrow_mut().poll(byte)
+    }
+
+    fn tick(&mut self) {
+        self.borrow_mut().tick()
+    }
+}
+
+// SC bits
+const START: u8 = 0x80;
+const FAST_CLOCK: u8 = 0x02;
+const INTERNAL_CLOCK: u8 = 0x01;
+
+// M-cycles per bit: 8192 Hz, or 262144 Hz with the CGB fast clock
+const BIT_CYCLES: u32 = 128;
+const FAST_BIT_CYCLES: u32 = 4;
+
+#[derive(Default)]
+pub struct Serial {
+    pub data: u8,    // SB
+    control: u8,     // SC
+    // The fast clock only exists on the CGB
+    pub cgb: bool,
+    pub device: Option<Box<dyn SerialDevice>>,
+    // Every byte sent, for debugging. Blargg's test ROMs print their results there.
+    pub sent: Vec<u8>,
+    // Byte being shifted in, bits left to shift, and M-cycles until the next one
+    incoming: u8,
+    bits: u8,
+    cycles: u32,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Unused bits read as 1
+    pub fn get_control(&self) -> u8 {
+        if self.cgb { self.control | 0x7C } else { self.control | 0x7E }
+    }
+
+    pub fn set_control(&mut self, value: u8) {
+        self.control = value;
+        self.bits = 0;
+
+        if value & (START | INTERNAL_CLOCK) == START | INTERNAL_CLOCK {
+            self.sent.push(self.data);
+            // Nothing on the other end reads as all ones
+            self.incoming = match self.device.as_mut() {
+                Some(device) => device.transfer(self.data),
+                None => 0xFF,
+            };
+            self.bits = 8;
+            self.cycles = self.bit_cycles();
+        }
+    }
+
+    fn bit_cycles(&self) -> u32 {
+        if self.cgb && self.control & FAST_CLOCK != 0 { FAST_BIT_CYCLES } else { BIT_CYCLES }
+    }
+
+    // Advance by one M-cycle. Returns true when a transfer is done, to request the interrupt.
+    pub fn tick(&mut self) -> bool {
+        if let Some(device) = self.device.as_mut() {
+            device.tick();
+        }
+
+        if self.control & START == 0 {
+            return false;
+        }
+
+        if self.control & INTERNAL_CLOCK == 0 {
+            let incoming = match self.device.as_mut() {
+                Some(device) => device.poll(self.data),
+                None => None,
+            };
+            return match incoming {
+                Some(byte) => {
+                    self.sent.push(self.data);
+                    self.data = byte;
+                    self.finish()
+                }
+                None => false,
+            };
+        }
+
+        self.cycles -= 1;
+        if self.cycles > 0 {
+            return false;
+        }
+
+        self.bits -= 1;
+        self.data = (self.data << 1) | ((self.incoming >> self.bits) & 1);
+        if self.bits > 0 {
+            self.cycles = self.bit_cycles();
+            return false;
+        }
+
+        self.finish()
+    }
+
+    fn finish(&mut self) -> bool {
+        self.control &= !START;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends back what it received the time before
+    #[derive(Clone, Default)]
+    struct Echo(Rc<RefCell<Vec<u8>>>);
+
+    impl SerialDevice for Echo {
+        fn transfer(&mut self, byte: u8) -> u8 {
+            let mut received = self.0.borrow_mut();
+            let previous = received.last().copied().unwrap_or(0);
+            received.push(byte);
+            previous
+        }
+    }
+
+    // Drives the clock, once
+    struct Master(Option<u8>);
+
+    impl SerialDevice for Master {
+        fn transfer(&mut self, _byte: u8) -> u8 {
+            0xFF
+        }
+
+        fn poll(&mut self, _byte: u8) -> Option<u8> {
+            self.0.take()
+        }
+    }
+
+    fn run(serial: &mut Serial, cycles: u32) -> bool {
+        (0..cycles).fold(false, |done, _| serial.tick() || done)
+    }
+
+    #[test]
+    fn test_internal_clock() {
+        let mut serial = Serial::new();
+        serial.data = 0x42;
+        serial.set_control(0x81);
+        assert!(!run(&mut serial, 8 * BIT_CYCLES - 1));
+        assert_eq!(serial.get_control(), 0xFF);
+        assert!(serial.tick());
+        assert_eq!(serial.data, 0xFF);
+        assert_eq!(serial.get_control(), 0x7F);
+        assert_eq!(serial.sent, vec![0x42]);
+    }
+
+    #[test]
+    fn test_shift() {
+        let echo = Echo::default();
+        let mut serial = Serial::new();
+        serial.device = Some(Box::new(echo.clone()));
+        serial.data = 0xA5;
+        serial.set_control(0x81);
+        run(&mut serial, 8 * BIT_CYCLES);
+
+        serial.data = 0x12;
+        serial.set_control(0x81);
+        run(&mut serial, 4 * BIT_CYCLES);
+        assert_eq!(serial.data, 0x2A);
+        run(&mut serial, 4 * BIT_CYCLES);
+        assert_eq!(serial.data, 0xA5);
+        assert_eq!(*echo.0.borrow(), vec![0xA5, 0x12]);
+    }
+
+    #[test]
+    fn test_fast_clock() {
+        let mut serial = Serial::new();
+        serial.set_control(0x83);
+        assert!(!run(&mut serial, 8 * FAST_BIT_CYCLES));
+        assert!(run(&mut serial, 8 * BIT_CYCLES));
+
+        serial.cgb = true;
+        serial.set_control(0x83);
+        assert!(run(&mut serial, 8 * FAST_BIT_CYCLES));
+    }
+
+    #[test]
+    fn test_external_clock() {
+        let mut serial = Serial::new();
+        serial.data = 0x42;
+        serial.set_control(0x80);
+        assert!(!run(&mut serial, 8 * BIT_CYCLES * 4));
+
+        serial.device = Some(Box::new(Master(Some(0x99))));
+        assert!(serial.tick());
+        assert_eq!(serial.data, 0x99);
+        assert_eq!(serial.control, 0x00);
+        assert_eq!(serial.sent, vec![0x42]);
+    }
+}
@@ -0,0 +1,118 @@
+// RGBDS symbol files: one "bank:addr label" per line, both in hex, ';' starts a comment.
+// https://rgbds.gbdev.io/sym/
+
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    by_name: HashMap<String, (usize, u16)>,
+    by_address: BTreeMap<(usize, u16), String>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Lines that don't look like a symbol are ignored
+    pub fn parse(text: &str) -> Self {
+        let mut symbols = Self::new();
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let mut parts = line.split_whitespace();
+            let (location, name) = match (parts.next(), parts.next()) {
+                (Some(location), Some(name)) => (location, name),
+                _ => continue,
+            };
+
+            let (bank, address) = match location.split_once(':') {
+                Some((bank, address)) => (bank, address),
+                None => continue,
+            };
+
+            if let (Ok(bank), Ok(address)) = (usize::from_str_radix(bank, 16), u16::from_str_radix(address, 16)) {
+                symbols.insert(bank, address, name);
+            }
+        }
+
+        symbols
+    }
+
+    // When several symbols share an address, the first one names it
+    pub fn insert(&mut self, bank: usize, address: u16, name: &str) {
+        self.by_name.insert(name.to_string(), (bank, address));
+        self.by_address.entry((bank, address)).or_insert_with(|| name.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    // Bank and address of a symbol
+    pub fn address(&self, name: &str) -> Option<(usize, u16)> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn name(&self, bank: usize, address: u16) -> Option<&str> {
+        self.by_address.get(&(bank, address)).map(String::as_str)
+    }
+
+    // Name of an address relative to the closest symbol before it in the same bank and
+    // the same 16KB region. E.g: "Main.loop" or "Main.loop+$3"
+    pub fn describe(&self, bank: usize, address: u16) -> Option<String> {
+        let region = (bank, address & 0xC000);
+        let (&(_, start), name) = self.by_address.range(region..=(bank, address)).next_back()?;
+
+        match address - start {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+${:X}", name, offset)),
+        }
+    }
+
+    // Symbols of a bank within an address range, in order
+    pub fn in_range(&self, bank: usize, start: u16, end: u16) -> impl Iterator<Item = (u16, &str)> {
+        self.by_address.range((bank, start)..=(bank, end)).map(|(&(_, address), name)| (address, name.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SYM: &str = "\
+; File generated by rgblink
+00:0150 Main
+00:0158 Main.loop
+01:4000 Banked
+00:c000 wCounter
+00:0158 Main.alias
+garbage
+";
+
+    #[test]
+    fn test_parse() {
+        let symbols = Symbols::parse(SYM);
+
+        assert_eq!(symbols.address("Main"), Some((0, 0x150)));
+        assert_eq!(symbols.address("Banked"), Some((1, 0x4000)));
+        assert_eq!(symbols.address("Main.alias"), Some((0, 0x158)));
+        assert_eq!(symbols.address("garbage"), None);
+        assert_eq!(symbols.name(0, 0x158), Some("Main.loop"));
+        assert_eq!(symbols.name(0, 0x4000), None);
+    }
+
+    #[test]
+    fn test_describe() {
+        let symbols = Symbols::parse(SYM);
+
+        assert_eq!(symbols.describe(0, 0x150).as_deref(), Some("Main"));
+        assert_eq!(symbols.describe(0, 0x15A).as_deref(), Some("Main.loop+$2"));
+        assert_eq!(symbols.describe(1, 0x4010).as_deref(), Some("Banked+$10"));
+        // Different region or bank
+        assert_eq!(symbols.describe(0, 0x4010), None);
+        assert_eq!(symbols.describe(2, 0x4010), None);
+        assert_eq!(symbols.describe(0, 0x100), None);
+        assert_eq!(symbols.describe(0, 0xC001).as_deref(), Some("wCounter+$1"));
+    }
+}
@@ -0,0 +1,180 @@
+// Instruction trace written while the CPU runs, one line per instruction
+// before it's executed.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    // Registers, flags, opcode bytes, mnemonic and symbol
+    Yeeboy,
+    // Same format as the reference logs of https://github.com/robert/gameboy-doctor
+    // A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+    Doctor,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "yeeboy" => Some(TraceFormat::Yeeboy),
+            "doctor" | "gameboy-doctor" => Some(TraceFormat::Doctor),
+            _ => None,
+        }
+    }
+}
+
+pub struct Tracer {
+    pub format: TraceFormat,
+    // Tracing starts when PC first reaches this address...
+    pub start_pc: Option<u16>,
+    // ...and/or when this many cycles have elapsed
+    pub start_cycle: Option<u64>,
+    // Stop tracing after this many lines
+    pub max_lines: Option<u64>,
+    writer: Box<dyn Write>,
+    started: bool,
+    lines: u64,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, format: TraceFormat) -> Self {
+        Self {
+            format,
+            start_pc: None,
+            start_cycle: None,
+            max_lines: None,
+            writer,
+            started: false,
+            lines: 0,
+        }
+    }
+
+    pub fn stdout(format: TraceFormat) -> Self {
+        Self::new(Box::new(io::stdout()), format)
+    }
+
+    pub fn file<P: AsRef<Path>>(path: P, format: TraceFormat) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(Box::new(BufWriter::new(file)), format))
+    }
+
+    pub fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.max_lines.map_or(false, |max| self.lines >= max)
+    }
+
+    // Whether the instruction at `pc` should be traced
+    pub fn should_trace(&mut self, pc: u16, cycles: u64) -> bool {
+        if self.is_done() {
+            return false;
+        }
+
+        if !self.started {
+            let pc_reached = self.start_pc.map_or(true, |start| start == pc);
+            let cycle_reached = self.start_cycle.map_or(true, |start| cycles >= start);
+            self.started = pc_reached && cycle_reached;
+        }
+
+        self.started
+    }
+
+    // A failing writer stops the trace
+    pub fn write(&mut self, line: &str) {
+        if writeln!(self.writer, "{}", line).is_err() {
+            self.max_lines = Some(self.lines);
+            return;
+        }
+
+        self.lines += 1;
+        if self.is_done() {
+            let _ = self.writer.flush();
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::{make_console, Console};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone()).unwrap().lines().map(String::from).collect()
+        }
+    }
+
+    // NOP; LD A, 0x42; JP 0x0100
+    fn make_traced_console(tracer: Tracer) -> Console {
+        let mut console = make_console(&[0x00, 0x3E, 0x42, 0xC3, 0x00, 0x01]);
+        console.cpu.tracer = Some(tracer);
+        console
+    }
+
+    #[test]
+    fn test_doctor_format() {
+        let buffer = Buffer::default();
+        let mut console = make_traced_console(Tracer::new(Box::new(buffer.clone()), TraceFormat::Doctor));
+        console.step();
+        console.step();
+
+        assert_eq!(buffer.lines(), vec![
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,3E,42,C3",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:3E,42,C3,00",
+        ]);
+    }
+
+    #[test]
+    fn test_start_and_limit() {
+        let buffer = Buffer::default();
+        let mut tracer = Tracer::new(Box::new(buffer.clone()), TraceFormat::Doctor);
+        tracer.start_pc = Some(0x103);
+        tracer.max_lines = Some(3);
+        let mut console = make_traced_console(tracer);
+        for _ in 0..10 {
+            console.step();
+        }
+
+        let pcs = buffer.lines().iter()
+            .map(|l| l.split(' ').find(|f| f.starts_with("PC:")).unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(pcs, vec!["PC:0103", "PC:0100", "PC:0101"]);
+    }
+
+    #[test]
+    fn test_start_cycle() {
+        let buffer = Buffer::default();
+        let mut tracer = Tracer::new(Box::new(buffer.clone()), TraceFormat::Yeeboy);
+        tracer.start_cycle = Some(12);
+        let mut console = make_traced_console(tracer);
+        for _ in 0..3 {
+            console.step();
+        }
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("0103: C3 00 01"), "{}", lines[0]);
+    }
+}